@@ -74,7 +74,7 @@ impl ValidatedInitFlags {
             },
             be_port: match init_flags.be_port {
                 Some(be_port) => (be_port, vec![]),
-                None => (9999, vec!["TODO implement BE".to_owned()])
+                None => (19132, vec!["Using default BE port 19132".to_owned()])
             },
             bind_addr: match &init_flags.bind_addr {
                 Some(addr) => (addr.to_owned(), vec![]),
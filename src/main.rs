@@ -58,6 +58,8 @@ fn main() {
             let (stdin_send, stdin_recv) = crossbeam::unbounded();
             let stdin = std::io::stdin();
             let (ctrl_c_send, ctrl_c_recv) = crossbeam::bounded(1);
+            // output of commands typed into stdin
+            let (console_send, console_recv) = crossbeam::unbounded();
             ctrlc::set_handler(move || {
                 ctrl_c_send.send(Instant::now());
             });
@@ -76,7 +78,21 @@ fn main() {
             loop {
                 crossbeam::channel::select! {
                     recv(stdin_recv) -> in_line => {
-                        debug!("stdin {}", in_line.unwrap());
+                        let line = in_line.unwrap();
+                        debug!("stdin {}", &line);
+                        if let Err(e) = chans.cli_send.send(CliCommand {
+                            line,
+                            reply: console_send.clone()
+                        }) {
+                            error!("Game thread gone, dropped command {:?}", e.0.line);
+                        }
+                    },
+                    recv(console_recv) -> output => {
+                        if let Ok(output) = output {
+                            if !output.is_empty() {
+                                info!("{}", output);
+                            }
+                        }
                     },
                     recv(&chans.cli_recv) -> gs_cli_msg => {
 
@@ -145,9 +161,32 @@ impl SrAllocator {
                 );
             }
         }
+        let (tick, net) = self.get_target_cores_dist();
+        info!("| Threads: {} tick, {} network", tick, net);
+        if tick + net >= self.total_threads {
+            warn!(">> config/performance: \
+                smp_threads_tick and smp_threads_net ({} + {}) leave no core for the game thread ({} available)",
+                tick,
+                net,
+                self.total_threads
+            );
+        }
     }
+    /// Threads for ticking and for network workers, as `(tick, net)`.
+    /// Counts set in the config are kept, otherwise the cores besides the game thread's are split,
+    /// roughly a quarter to the network.
     pub fn get_target_cores_dist(&self) -> (usize, usize) {
-        todo!()
+        let spare = self.total_threads.saturating_sub(1).max(1);
+        let net = match (self.config_perf.smp_threads_net, self.config_perf.smp_threads_tick) {
+            (Some(net), _) => net as usize,
+            (None, Some(tick)) => spare.saturating_sub(tick as usize),
+            (None, None) => spare / 4
+        }.max(1);
+        let tick = match self.config_perf.smp_threads_tick {
+            Some(tick) => tick as usize,
+            None => spare.saturating_sub(net)
+        }.max(1);
+        (tick, net)
     }
 }
 
@@ -1,6 +1,6 @@
 use crate::server::net::*;
-use std::{net::SocketAddr, error::Error};
-use tokio::sync::mpsc::UnboundedSender;
+use std::{net::SocketAddr, error::Error, time::Duration, sync::{Arc, atomic::{AtomicU32, Ordering}}};
+use openssl::{pkey::Private, rsa::{Padding, Rsa}};
 
 // JE login process
 /**
@@ -56,50 +56,81 @@ use tokio::sync::mpsc::UnboundedSender;
     Ok(JeSession {})
 }*/
 
+#[derive(Debug)]
 pub enum JeLoginError {
     PacketError,
-    Internal(Box<dyn Error>)
+    VerifyTokenMismatch,
+    Internal(Box<dyn Error + Send + Sync>)
+}
+
+/// Decrypt an Encryption Response with the server keypair.
+/// Returns the shared secret if the verify token matches the one sent in the Encryption Request.
+pub fn decrypt_enc_response(keypair: &Rsa<Private>, resp: &JeEncResponse, vtoken: &[u8]) -> Result<[u8; 16], JeLoginError> {
+    let rsa_decrypt = |data: &[u8]| -> Result<Vec<u8>, JeLoginError> {
+        let mut buf = vec![0u8; keypair.size() as usize];
+        let len = keypair.private_decrypt(data, &mut buf, Padding::PKCS1)
+            .map_err(|e| JeLoginError::Internal(e.into()))?;
+        buf.truncate(len);
+        Ok(buf)
+    };
+    if rsa_decrypt(&resp.vtoken)? != vtoken {
+        return Err(JeLoginError::VerifyTokenMismatch);
+    }
+    let secret = rsa_decrypt(&resp.shared_secret)?;
+    if secret.len() != 16 {
+        return Err(JeLoginError::PacketError);
+    }
+    let mut shared_secret = [0u8; 16];
+    shared_secret.copy_from_slice(&secret);
+    Ok(shared_secret)
 }
 
 #[derive(Debug, Clone)]
 pub struct JeConnection {
-    pub state: i32,
+    pub state: ConnectionState,
     pub enc: Option<JeSessionEncrypt>,
     pub uuid: uuid::Uuid,
     pub username: String,
     pub addr: SocketAddr,
-    pub send: UnboundedSender<(i32, Vec<u8>)>,
-    pub online: bool
+    pub outbound: Arc<JeOutboundQueue>,
+    pub online: bool,
+    /// Profile properties (skin, cape) from the session server.
+    pub properties: Vec<JeProfileProperty>,
+    /// Last keep-alive round trip in milliseconds, updated by the connection task.
+    pub latency: Arc<AtomicU32>
 }
 
+/// Packets are queued unencrypted, the connection task encrypts them on the way out if `enc` is set.
 impl JeConnection {
     pub fn send<T: JePacket>(&self, packet: T) {
-        if let Some(e) = &self.enc {
-            unimplemented!()
-        } else {
-            self.send.send((
-                packet.get_packet_id().0,
-                packet.to_vec_u8()
-            ));
-        }
+        self.outbound.push(
+            packet.get_packet_id().0,
+            packet.to_vec_u8()
+        );
     }
     pub fn send_raw(&self, packet_id: i32, data: &[u8]) {
-        if let Some(e) = &self.enc {
-            unimplemented!()
-        } else {
-            self.send.send((
-                packet_id,
-                data.to_owned()
-            ));
-        }
+        self.outbound.push(
+            packet_id,
+            data.to_owned()
+        );
+    }
+    pub fn latency(&self) -> Duration {
+        Duration::from_millis(self.latency.load(Ordering::Relaxed) as u64)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct JeSessionEncrypt {
-    vtoken: [u8; 4]
+    pub shared_secret: [u8; 16]
+}
+
+impl std::fmt::Debug for JeSessionEncrypt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // keep the secret out of the logs
+        write!(f, "JeSessionEncrypt {{ .. }}")
+    }
 }
 
 pub fn jestring_to_string(data: &[u8]) -> String {
     todo!()
-}
\ No newline at end of file
+}
\ No newline at end of file
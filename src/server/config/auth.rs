@@ -1,11 +1,23 @@
 use serde::{Serialize, Deserialize};
 use super::ConfigFile;
 
+config_defaults!(ConfigAuth {
+    session_server: String,
+    session_server_mock: bool
+});
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ConfigAuth {
     pub online_mode: bool,
     pub max_players: u8,
-    pub default_world_name: String
+    pub default_world_name: String,
+    /// Base URL of the session server used to verify online players.
+    #[serde(default = "defaults::session_server")]
+    pub session_server: String,
+    /// Verify against a local stand-in session server instead, accepting any username.
+    /// For testing online mode without network access.
+    #[serde(default = "defaults::session_server_mock")]
+    pub session_server_mock: bool
 }
 
 impl Default for ConfigAuth {
@@ -13,7 +25,9 @@ impl Default for ConfigAuth {
         Self {
             online_mode: true,
             max_players: 20,
-            default_world_name: "overworld".to_owned()
+            default_world_name: "overworld".to_owned(),
+            session_server: "https://sessionserver.mojang.com".to_owned(),
+            session_server_mock: false
         }
     }
 }
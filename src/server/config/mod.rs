@@ -1,3 +1,18 @@
+/// `#[serde(default = "defaults::field")]` functions for fields added after a config was first
+/// written, so existing config files still load. Each takes the field's value from `Default`.
+macro_rules! config_defaults {
+    ($config:ident { $($field:ident: $field_type:ty),* }) => {
+        mod defaults {
+            use super::*;
+            $(
+                pub(super) fn $field() -> $field_type {
+                    $config::default().$field
+                }
+            )*
+        }
+    };
+}
+
 mod auth;
 mod cap;
 mod exp;
@@ -9,7 +24,7 @@ pub use self::auth::ConfigAuth;
 pub use self::cap::ConfigCap;
 pub use self::exp::ConfigExp;
 pub use self::init::ConfigInit;
-pub use self::net::ConfigNet;
+pub use self::net::{ConfigNet, ProxyForwarding, SlowClientPolicy};
 pub use self::perf::ConfigPerf;
 
 use std::{path::{Path, PathBuf}, error::Error};
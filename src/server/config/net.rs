@@ -1,13 +1,156 @@
 use serde::{Serialize, Deserialize};
 use super::ConfigFile;
 
+config_defaults!(ConfigNet {
+    compression_threshold: Option<usize>,
+    compression_level: u32,
+    max_packet_len: usize,
+    keep_alive_interval_secs: u64,
+    timeout_secs: u64,
+    login_timeout_secs: u64,
+    query_port: Option<u16>,
+    query_token_secs: u64,
+    rcon_port: Option<u16>,
+    rcon_password: String,
+    rcon_max_auth_failures: u32,
+    rcon_lockout_secs: u64,
+    status_sample_len: usize,
+    status_hide_players: bool,
+    forwarding: ProxyForwarding,
+    velocity_secret: String,
+    proxy_protocol: bool,
+    proxy_protocol_trusted: Vec<String>,
+    proxy_protocol_allow_direct: bool,
+    throttle_window_secs: u64,
+    max_connections_per_ip: u32,
+    max_logins_per_ip: u32,
+    max_pending_connections: usize,
+    slow_packet_timeout_secs: u64,
+    throttle_offenses_before_block: u32,
+    throttle_block_secs: u64,
+    capture_packets: bool,
+    outbound_budget_bytes: usize,
+    slow_client_policy: SlowClientPolicy
+});
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ConfigNet {
     pub sync_async_channel_len: usize,
     pub web_addr_port: String,
+    /// Disconnect clients sending packets that fail to decode, instead of skipping the packet.
     pub kick_invalid_packet: bool,
     pub server_name: String,
-    pub server_description: String
+    pub server_description: String,
+    /// Packets at least this many bytes long are compressed once logged in. `None` disables compression.
+    #[serde(default = "defaults::compression_threshold")]
+    pub compression_threshold: Option<usize>,
+    /// zlib compression level, 0-9.
+    #[serde(default = "defaults::compression_level")]
+    pub compression_level: u32,
+    /// Clients sending a longer packet are disconnected.
+    #[serde(default = "defaults::max_packet_len")]
+    pub max_packet_len: usize,
+    /// Seconds between keep-alives sent to players.
+    #[serde(default = "defaults::keep_alive_interval_secs")]
+    pub keep_alive_interval_secs: u64,
+    /// Players silent for this many seconds are disconnected.
+    #[serde(default = "defaults::timeout_secs")]
+    pub timeout_secs: u64,
+    /// Connections that haven't reached play state after this many seconds are closed.
+    #[serde(default = "defaults::login_timeout_secs")]
+    pub login_timeout_secs: u64,
+    /// UDP port for the GameSpy4 query protocol. `None` disables it.
+    #[serde(default = "defaults::query_port")]
+    pub query_port: Option<u16>,
+    /// Seconds a query challenge token stays valid, at least.
+    #[serde(default = "defaults::query_token_secs")]
+    pub query_token_secs: u64,
+    /// TCP port for Source RCON. `None` disables it.
+    #[serde(default = "defaults::rcon_port")]
+    pub rcon_port: Option<u16>,
+    /// RCON refuses every login while this is empty.
+    #[serde(default = "defaults::rcon_password")]
+    pub rcon_password: String,
+    /// Failed RCON logins before an address is locked out.
+    #[serde(default = "defaults::rcon_max_auth_failures")]
+    pub rcon_max_auth_failures: u32,
+    #[serde(default = "defaults::rcon_lockout_secs")]
+    pub rcon_lockout_secs: u64,
+    /// Players listed when hovering over the player count.
+    #[serde(default = "defaults::status_sample_len")]
+    pub status_sample_len: usize,
+    /// List anonymous players instead of names.
+    #[serde(default = "defaults::status_hide_players")]
+    pub status_hide_players: bool,
+    /// Take players' identity and address from a proxy in front of the server.
+    /// Anyone who can reach the JE port directly can claim any identity, firewall it.
+    #[serde(default = "defaults::forwarding")]
+    pub forwarding: ProxyForwarding,
+    /// Shared with Velocity's `forwarding-secret`.
+    #[serde(default = "defaults::velocity_secret")]
+    pub velocity_secret: String,
+    /// Read HAProxy PROXY protocol headers on the JE port, for servers behind a TCP load balancer.
+    /// Connections without a header are closed, unless `proxy_protocol_allow_direct` is set.
+    #[serde(default = "defaults::proxy_protocol")]
+    pub proxy_protocol: bool,
+    /// Addresses or CIDR ranges allowed to send PROXY headers. Connections from others sending one are closed.
+    #[serde(default = "defaults::proxy_protocol_trusted")]
+    pub proxy_protocol_trusted: Vec<String>,
+    /// With `proxy_protocol`, also accept connections without a header as direct clients.
+    /// Without it, untrusted addresses are closed on accept, so clients can't get around the balancer.
+    #[serde(default = "defaults::proxy_protocol_allow_direct")]
+    pub proxy_protocol_allow_direct: bool,
+    /// Seconds over which the per-address connection and login limits are counted.
+    #[serde(default = "defaults::throttle_window_secs")]
+    pub throttle_window_secs: u64,
+    /// New connections an address may open per window, 0 for no limit.
+    /// Not applied to forwarding proxies, their players are limited at login instead.
+    #[serde(default = "defaults::max_connections_per_ip")]
+    pub max_connections_per_ip: u32,
+    /// Login attempts an address may make per window, 0 for no limit.
+    #[serde(default = "defaults::max_logins_per_ip")]
+    pub max_logins_per_ip: u32,
+    /// Connections not yet in play state, from all addresses together. Others are closed on accept. 0 for no cap.
+    #[serde(default = "defaults::max_pending_connections")]
+    pub max_pending_connections: usize,
+    /// Clients taking longer than this many seconds to finish sending a packet are disconnected.
+    #[serde(default = "defaults::slow_packet_timeout_secs")]
+    pub slow_packet_timeout_secs: u64,
+    /// Throttled, slow or late connections from one address within `throttle_block_secs`
+    /// before the address is blocked for that long. 0 never blocks.
+    #[serde(default = "defaults::throttle_offenses_before_block")]
+    pub throttle_offenses_before_block: u32,
+    #[serde(default = "defaults::throttle_block_secs")]
+    pub throttle_block_secs: u64,
+    /// Record every JE connection's packets to `captures/` in the prefix, for replaying.
+    /// Captures are decrypted and hold everything players send, only enable while debugging.
+    #[serde(default = "defaults::capture_packets")]
+    pub capture_packets: bool,
+    /// Bytes queued for a player before `slow_client_policy` applies.
+    #[serde(default = "defaults::outbound_budget_bytes")]
+    pub outbound_budget_bytes: usize,
+    #[serde(default = "defaults::slow_client_policy")]
+    pub slow_client_policy: SlowClientPolicy
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyForwarding {
+    None,
+    /// BungeeCord `ip_forward`, also Velocity's `legacy` mode.
+    BungeeCord,
+    /// Velocity `modern` mode, signed with `velocity_secret`.
+    Velocity
+}
+
+/// What happens to players who can't keep up with what they're sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowClientPolicy {
+    /// Disconnect them with "Network too slow".
+    Disconnect,
+    /// Drop packets they can do without, disconnect if that's not enough.
+    DropLowPriority
 }
 
 impl Default for ConfigNet {
@@ -17,7 +160,36 @@ impl Default for ConfigNet {
             web_addr_port: "127.0.0.1:8080".to_owned(),
             kick_invalid_packet: false,
             server_name: crate::SERVER_RELNAME.to_owned(),
-            server_description: format!("A CraftMine server ({})", crate::SERVER_RELNAME)
+            server_description: format!("A CraftMine server ({})", crate::SERVER_RELNAME),
+            compression_threshold: Some(256),
+            compression_level: 6,
+            max_packet_len: 2097151,
+            keep_alive_interval_secs: 15,
+            timeout_secs: 30,
+            login_timeout_secs: 30,
+            query_port: None,
+            query_token_secs: 30,
+            rcon_port: None,
+            rcon_password: "".to_owned(),
+            rcon_max_auth_failures: 3,
+            rcon_lockout_secs: 300,
+            status_sample_len: 12,
+            status_hide_players: false,
+            forwarding: ProxyForwarding::None,
+            velocity_secret: "".to_owned(),
+            proxy_protocol: false,
+            proxy_protocol_trusted: vec!["127.0.0.1".to_owned(), "::1".to_owned()],
+            proxy_protocol_allow_direct: false,
+            throttle_window_secs: 10,
+            max_connections_per_ip: 10,
+            max_logins_per_ip: 3,
+            max_pending_connections: 256,
+            slow_packet_timeout_secs: 10,
+            throttle_offenses_before_block: 5,
+            throttle_block_secs: 300,
+            capture_packets: false,
+            outbound_budget_bytes: 16 * 1024 * 1024,
+            slow_client_policy: SlowClientPolicy::DropLowPriority
         }
     }
 }
@@ -26,4 +198,27 @@ impl ConfigFile for ConfigNet {
     fn get_filename() -> &'static str {
         "network.json"
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_config_without_new_fields() {
+        // network.json as written before any of the defaulted fields existed
+        let old = serde_json::json!({
+            "sync_async_channel_len": 100,
+            "web_addr_port": "127.0.0.1:8080",
+            "kick_invalid_packet": true,
+            "server_name": "old",
+            "server_description": "An old server"
+        });
+        let net: ConfigNet = serde_json::from_value(old).unwrap();
+        let default = ConfigNet::default();
+        assert_eq!(net.sync_async_channel_len, 100);
+        assert_eq!(net.server_name, "old");
+        assert_eq!(net.compression_threshold, default.compression_threshold);
+        assert_eq!(net.proxy_protocol_trusted, default.proxy_protocol_trusted);
+        assert_eq!(net.slow_client_policy, default.slow_client_policy);
+    }
+}
@@ -3,6 +3,10 @@ use crate::server::symbols::*;
 
 use serde::{Serialize, Deserialize};
 
+config_defaults!(ConfigPerf {
+    smp_threads_net: Option<u64>
+});
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ConfigPerf {
     pub view_distance_chunks: u16,
@@ -11,6 +15,8 @@ pub struct ConfigPerf {
     pub chunks_active_max: Option<u64>,
     pub spawn_active_keep: bool,
     pub smp_threads_tick: Option<u64>,
+    #[serde(default = "defaults::smp_threads_net")]
+    pub smp_threads_net: Option<u64>,
     pub chunks_pools: ConfigChunkPools,
     pub target_tick_s_f64: f64,
     pub cg_causality_map: HashMap<BlockId, u64>
@@ -25,6 +31,7 @@ impl Default for ConfigPerf {
             chunks_active_max: Some(32),
             spawn_active_keep: true,
             smp_threads_tick: None,
+            smp_threads_net: None,
             chunks_pools: ConfigChunkPools::default(),
             target_tick_s_f64: 0.05f64,
             cg_causality_map: [
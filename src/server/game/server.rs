@@ -6,7 +6,7 @@ pub struct GameServer {
     pub prefix: ServerPrefix,
     pub init_flags: ValidatedInitFlags,
     pub worlds: HashMap<String, World>,
-    pub cli_recv: crossbeam::Receiver<String>,
+    pub cli_recv: crossbeam::Receiver<CliCommand>,
     pub cli_send: crossbeam::Sender<String>,
     pub async_net_instance: NetServer,
     pub cc: ConfigCollection,
@@ -53,7 +53,15 @@ impl GameServer {
                         }
                     }
                 }
-                recv(self.tick) -> _ => self.process_tick()
+                recv(self.tick) -> _ => self.process_tick(),
+                recv(self.cli_recv) -> cmd => {
+                    if let Ok(cmd) = cmd {
+                        let output = self.process_command(&cmd.line);
+                        if cmd.reply.send(output).is_err() {
+                            warn!("Nowhere to send the output of {:?}, the console or RCON client is gone", &cmd.line);
+                        }
+                    }
+                }
             }
         }
     }
@@ -64,7 +72,8 @@ impl GameServer {
             match inc_net_packet.inner {
                 NetRecvInner::NewSession {
                     username: u,
-                    online: online
+                    online: online,
+                    ..
                 } => {
                     if let Ok(user) = match online {
                         true => {
@@ -93,7 +102,7 @@ impl GameServer {
                     }
                 },
                 NetRecvInner::EndSession => {
-                    match self.users.get(&inc_net_packet.uuid) {
+                    match self.users.remove(&inc_net_packet.uuid) {
                         Some(u) => {
                             info!("{} ({}) has left.", u.username, &inc_net_packet.uuid);
                         },
@@ -102,8 +111,108 @@ impl GameServer {
                         }
                     }
                 },
-                _ => ()
+                NetRecvInner::Packet { id, data } => {
+                    self.process_packet(&inc_net_packet.uuid, id, &data);
+                }
+            }
+        }
+    }
+    /// Dispatch a play packet from `uuid`.
+    pub fn process_packet(&mut self, uuid: &Uuid, id: i32, data: &[u8]) {
+        let username = match self.users.get(uuid) {
+            Some(u) => u.username.clone(),
+            None => {
+                warn!("Packet {:#04x} from unknown uuid {}", id, uuid);
+                return;
+            }
+        };
+        let decoded = match id {
+            0x03 => JePlayChatIn::try_from_raw(data).map(|pk| {
+                info!("<{}> {}", &username, &pk.message);
+            }),
+            0x11 => JePlayerPosition::try_from_raw(data).map(|pk| {
+                debug!("{} moved to ({}, {}, {})", &username, pk.x, pk.feet_y, pk.z);
+            }),
+            0x12 => JePlayerPositionRotation::try_from_raw(data).map(|pk| {
+                debug!("{} moved to ({}, {}, {}) facing ({}, {})", &username, pk.x, pk.feet_y, pk.z, pk.yaw, pk.pitch);
+            }),
+            0x13 => JePlayerRotation::try_from_raw(data).map(|pk| {
+                debug!("{} facing ({}, {})", &username, pk.yaw, pk.pitch);
+            }),
+            0x14 => JePlayerMovement::try_from_raw(data).map(|_| ()),
+            _ => {
+                debug!("{} sent unhandled play packet {:#04x}", &username, id);
+                Ok(())
             }
+        };
+        if let Err(e) = decoded {
+            if self.cc.net.kick_invalid_packet {
+                info!("{} sent an invalid packet {:#04x}: {}, disconnecting", &username, id, e);
+                self.async_net_instance.disconnect(uuid, &format!("Invalid packet: {}", e));
+            } else {
+                debug!("DE: play packet {:#04x} from {}: {}, skipping", id, &username, e);
+            }
+        }
+    }
+    /// Run a console command, returning its output.
+    pub fn process_command(&mut self, line: &str) -> String {
+        let mut args = line.trim().splitn(2, ' ');
+        let cmd = args.next().unwrap_or("");
+        let rest = args.next().unwrap_or("").trim();
+        match cmd {
+            "help" => "Commands: help, list, netstats, replay <capture>, say <message>, kick <player> [reason]".to_owned(),
+            "list" => {
+                let mut names: Vec<&str> = self.users.values().map(|u| u.username.as_str()).collect();
+                names.sort();
+                format!("There are {} of a max of {} players online: {}", names.len(), self.cc.auth.max_players, names.join(", "))
+            },
+            "netstats" => {
+                let mut lines = vec![format!("Connections: {}", self.async_net_instance.throttle_stats.summary())];
+                for (username, queue) in self.async_net_instance.outbound_queues() {
+                    lines.push(format!(
+                        "{}: {} packets ({} bytes) queued, {} dropped",
+                        username, queue.queued_packets(), queue.queued_bytes(), queue.dropped()
+                    ));
+                }
+                lines.join("\n")
+            },
+            "replay" if !rest.is_empty() => {
+                // relative to the prefix, where captures are written
+                match capture_path(&self.prefix.path, rest) {
+                    Ok(capture) => {
+                        self.async_net_instance.replay(&capture);
+                        format!("Replaying {:?}, see the log for results", capture)
+                    },
+                    Err(e) => format!("Can't replay {}: {}", rest, e)
+                }
+            },
+            "say" if !rest.is_empty() => {
+                let to: Vec<Uuid> = self.users.keys().cloned().collect();
+                self.async_net_instance.broadcast(&to, JePlayChatOut {
+                    message: JeChat(JeChatComponent::text("")
+                        .append(JeChatComponent::text("[Server] ").color(JeChatColor::LightPurple))
+                        .append(JeChatComponent::from_legacy(rest, &[LEGACY_SECTION, LEGACY_AMPERSAND]))),
+                    position: 0
+                });
+                format!("[Server] {}", rest)
+            },
+            "kick" if !rest.is_empty() => {
+                let mut kick_args = rest.splitn(2, ' ');
+                let name = kick_args.next().unwrap_or("");
+                let reason = kick_args.next().unwrap_or("Kicked by an operator");
+                let target = self.users.iter()
+                    .find(|(_, u)| u.username.eq_ignore_ascii_case(name))
+                    .map(|(uuid, u)| (uuid.clone(), u.username.clone()));
+                match target {
+                    Some((uuid, username)) => {
+                        self.async_net_instance.disconnect(&uuid, reason);
+                        format!("Kicked {}: {}", username, reason)
+                    },
+                    None => format!("No player named {}", name)
+                }
+            },
+            "" => "".to_owned(),
+            _ => format!("Unknown command {}, try help", cmd)
         }
     }
     pub fn stop(&mut self) {
@@ -50,7 +50,7 @@ impl ServerInitializer {
                 infos.push(format!("JE: port {} available", validated_flags.je_port.0));
             }
 
-            if let Err(_) = TcpListener::bind(
+            if let Err(_) = std::net::UdpSocket::bind(
                 format!("127.0.0.1:{}", validated_flags.be_port.0)
             ) {
                 errs.push(
@@ -86,15 +86,16 @@ impl ServerInitializer {
                 let cc = cc_maybe.unwrap();
                 let (pfx, pfx_info) = ServerPrefix::load_or_new(&validated_flags.prefix.0);
 
-                let async_net_instance = NetServer::new(validated_flags.clone(), cc.clone(), pfx.clone());
-
                 let (cli_send, gs_cli_recv) = crossbeam::unbounded();
                 let (gs_cli_send, cli_recv) = crossbeam::unbounded();
 
-                let web_ws = cc.net.web_addr_port.clone();
-
                 let sra = SrAllocator::new(&cc);
                 sra.report();
+                let (_, net_threads) = sra.get_target_cores_dist();
+
+                let async_net_instance = NetServer::new(validated_flags.clone(), cc.clone(), pfx.clone(), cli_send.clone(), net_threads);
+
+                let web_ws = cc.net.web_addr_port.clone();
 
                 if cc.auth.online_mode == false {
                     warn!("Starting server in offline mode. Cannot verify users.");
@@ -163,12 +164,19 @@ pub struct ServerInitResult {
 
 pub struct ServerInitChannels {
     pub cli_recv: crossbeam::Receiver<String>,
-    pub cli_send: crossbeam::Sender<String>,
+    pub cli_send: crossbeam::Sender<CliCommand>,
     pub web_ws: String,
     pub send_status: crossbeam::Sender<ServerStatus>,
     pub recv_status: crossbeam::Receiver<ServerStatus>
 }
 
+/// A console line for the game thread, from stdin or RCON.
+pub struct CliCommand {
+    pub line: String,
+    /// Receives the command's output.
+    pub reply: crossbeam::Sender<String>
+}
+
 #[derive(Debug)]
 pub enum ServerStatus {
     Start,
@@ -4,17 +4,52 @@ mod auth;
 mod io;
 
 mod net {
+    mod bedrock;
+    mod capture;
+    mod chat;
+    mod client;
+    mod codec;
+    mod crypt;
+    mod forwarding;
+    mod handlers;
     mod je;
     pub mod legacy;
+    mod legacy_ping;
+    mod moderation;
     mod msg;
+    mod outbound;
     mod packets;
+    mod proxy_protocol;
+    mod query;
+    mod rcon;
     mod server;
+    mod session;
+    mod state;
+    mod throttle;
     mod types;
+    mod version;
+    pub use self::bedrock::*;
+    pub use self::capture::*;
+    pub use self::chat::*;
+    pub use self::client::*;
+    pub use self::codec::*;
+    pub use self::crypt::*;
+    pub use self::forwarding::*;
     pub use self::je::*;
+    pub use self::legacy_ping::*;
+    pub use self::moderation::*;
     pub use self::msg::*;
+    pub use self::outbound::*;
     pub use self::packets::*;
+    pub use self::proxy_protocol::*;
+    pub use self::query::*;
+    pub use self::rcon::*;
     pub use self::server::*;
+    pub use self::session::*;
+    pub use self::state::*;
+    pub use self::throttle::*;
     pub use self::types::*;
+    pub use self::version::*;
 }
 
 mod world {
@@ -56,6 +91,9 @@ mod records;
 
 pub mod log;
 
+#[cfg(test)]
+mod tests;
+
 /// A type which contains data loaded from the program memory.
 /// The name is slightly misleading and is only a weak guarantee; `Live` does *not* take ownership.
 /// It's only meant to be used in a function signature, and can be an out-of-sync clone of the actual object.
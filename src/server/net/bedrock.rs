@@ -0,0 +1,138 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use std::sync::Arc;
+use crossbeam::sync::ShardedLock;
+use tokio::net::UdpSocket;
+
+/// Marks RakNet offline messages.
+const RAKNET_MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe,
+    0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78
+];
+const RAKNET_UNCONNECTED_PING: u8 = 0x01;
+/// Only answered if the server takes new connections, which it always does.
+const RAKNET_UNCONNECTED_PING_OPEN: u8 = 0x02;
+const RAKNET_UNCONNECTED_PONG: u8 = 0x1c;
+/// Bedrock version shown in the server list. Clients on others see the server as incompatible.
+const BE_PROTOCOL: u32 = 390;
+const BE_VERSION: &str = "1.14.60";
+
+/// Gets every datagram that isn't an unconnected ping, i.e. everything needed for connected sessions.
+pub trait BeSessionHandler: Send {
+    /// Returns the datagrams to send back to `addr`.
+    fn on_datagram(&mut self, addr: SocketAddr, datagram: &[u8]) -> Vec<Vec<u8>>;
+}
+
+/// Until Bedrock play is implemented. Clients time out trying to join.
+pub struct BeNoSessions;
+
+impl BeSessionHandler for BeNoSessions {
+    fn on_datagram(&mut self, addr: SocketAddr, datagram: &[u8]) -> Vec<Vec<u8>> {
+        debug!("BE: {} sent {} bytes, connected sessions not supported", &addr, datagram.len());
+        vec![]
+    }
+}
+
+/// RakNet listener on `be_port`, enough to show up in Bedrock server lists.
+pub struct BeServer {
+    socket: UdpSocket,
+    port: u16,
+    /// Identifies this server to clients, random per start.
+    guid: u64,
+    world_name: String,
+    status: Arc<ShardedLock<ServerJsonStatus>>,
+    sessions: Box<dyn BeSessionHandler>
+}
+
+impl BeServer {
+    pub async fn bind(
+        bind_addr: &str,
+        be_port: u16,
+        world_name: &str,
+        status: Arc<ShardedLock<ServerJsonStatus>>,
+        sessions: Box<dyn BeSessionHandler>
+    ) -> std::io::Result<BeServer> {
+        let socket = UdpSocket::bind(format!("{}:{}", bind_addr, be_port)).await?;
+        info!("BE listening on {}", socket.local_addr()?);
+        let mut guid = [0u8; 8];
+        let guid = match openssl::rand::rand_bytes(&mut guid) {
+            Ok(()) => u64::from_be_bytes(guid),
+            Err(e) => {
+                // only has to differ between starts, the time will do
+                warn!("Failed to generate a random BE server GUID, using the time instead: {:?}", e);
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or(0)
+            }
+        };
+        Ok(Self {
+            socket,
+            port: be_port,
+            guid,
+            world_name: world_name.to_owned(),
+            status,
+            sessions
+        })
+    }
+    pub async fn run(mut self) {
+        // largest MTU clients try during connection
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(recv) => recv,
+                Err(e) => {
+                    warn!("BE socket error: {:?}", e);
+                    continue;
+                }
+            };
+            let replies = match buf[..len].first() {
+                Some(&RAKNET_UNCONNECTED_PING) | Some(&RAKNET_UNCONNECTED_PING_OPEN) => {
+                    self.pong(&buf[..len]).into_iter().collect()
+                },
+                Some(_) => self.sessions.on_datagram(addr, &buf[..len]),
+                None => vec![]
+            };
+            for reply in replies {
+                if let Err(e) = self.socket.send_to(&reply, &addr).await {
+                    debug!("BE reply to {} failed: {:?}", &addr, e);
+                }
+            }
+        }
+    }
+    /// Unconnected Pong for a well-formed ping, echoing its timestamp.
+    fn pong(&self, ping: &[u8]) -> Option<Vec<u8>> {
+        // id, time, magic, client guid
+        if ping.len() < 33 || ping[9..25] != RAKNET_MAGIC {
+            return None;
+        }
+        let motd = self.motd();
+        let mut pong = Vec::with_capacity(35 + motd.len());
+        pong.push(RAKNET_UNCONNECTED_PONG);
+        pong.extend_from_slice(&ping[1..9]);
+        pong.extend_from_slice(&self.guid.to_be_bytes());
+        pong.extend_from_slice(&RAKNET_MAGIC);
+        pong.extend_from_slice(&(motd.len() as u16).to_be_bytes());
+        pong.extend_from_slice(motd.as_bytes());
+        Some(pong)
+    }
+    /// `;` separated, in the order Bedrock expects.
+    fn motd(&self) -> String {
+        let status = self.status.read().unwrap();
+        // first line only, and no separators
+        let desc = status.desc.to_plain();
+        let desc = desc.lines().next().unwrap_or("").replace(';', "");
+        format!(
+            "MCPE;{};{};{};{};{};{};{};Survival;1;{};{};",
+            desc,
+            BE_PROTOCOL,
+            BE_VERSION,
+            status.online_players,
+            status.max_players,
+            self.guid,
+            self.world_name.replace(';', ""),
+            self.port,
+            self.port
+        )
+    }
+}
@@ -0,0 +1,288 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use std::{fs::File, io::BufWriter};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio_util::codec::Framed;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JeCaptureDirection {
+    /// From the client.
+    Inbound,
+    Outbound
+}
+
+/// One frame as it went over the wire, decrypted and decompressed.
+/// A capture file has one of these per line, as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JeCaptureRecord {
+    /// Milliseconds since the capture started.
+    pub at_ms: u64,
+    pub dir: JeCaptureDirection,
+    pub state: ConnectionState,
+    /// Wire id, before translating to or after translating from 1.15.2.
+    pub id: i32,
+    #[serde(with = "hex_bytes")]
+    pub data: Vec<u8>
+}
+
+/// Appends the frames of one connection to a capture file.
+pub struct JeCaptureWriter {
+    started: Instant,
+    path: PathBuf,
+    out: BufWriter<File>
+}
+
+impl JeCaptureWriter {
+    /// New capture file in `dir`, named after the current time and `addr`.
+    pub fn create(dir: &Path, addr: &SocketAddr) -> std::io::Result<JeCaptureWriter> {
+        std::fs::create_dir_all(dir)?;
+        let unix_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        // no colons or brackets in file names
+        let addr = addr.to_string().replace(|c| c == ':' || c == '[' || c == ']', "_");
+        let path = dir.join(format!("{}-{}.jsonl", unix_ms, addr));
+        let out = BufWriter::new(File::create(&path)?);
+        Ok(Self {
+            started: Instant::now(),
+            path,
+            out
+        })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Flushed straight away so the capture survives a crash.
+    pub fn record(&mut self, dir: JeCaptureDirection, state: ConnectionState, id: i32, data: &[u8]) -> std::io::Result<()> {
+        let record = JeCaptureRecord {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            dir,
+            state,
+            id,
+            data: data.to_vec()
+        };
+        serde_json::to_writer(&mut self.out, &record)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
+
+/// Where captures are written, in the prefix.
+pub const JE_CAPTURES_DIR: &str = "captures";
+
+/// Resolve `name`, relative to the prefix, to a capture file.
+/// Anything outside the captures directory is refused, `replay` can be run over RCON.
+pub fn capture_path(prefix: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let captures = prefix.join(JE_CAPTURES_DIR).canonicalize()?;
+    let path = prefix.join(name).canonicalize()?;
+    if path.starts_with(&captures) && path.is_file() {
+        Ok(path)
+    } else {
+        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "not a capture file"))
+    }
+}
+
+/// Read a capture file, skipping blank lines.
+pub fn read_capture(path: &Path) -> std::io::Result<Vec<JeCaptureRecord>> {
+    let text = std::fs::read_to_string(path)?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(std::io::Error::from))
+        .collect()
+}
+
+/// Where a replay disagrees with the capture. `index` is the record's position in the capture.
+#[derive(Debug)]
+pub enum JeReplayDiff {
+    /// The server sent a different frame.
+    Changed {
+        index: usize,
+        expected: JeCaptureRecord,
+        id: i32,
+        data: Vec<u8>
+    },
+    /// The server closed the connection or went quiet instead.
+    Missing {
+        index: usize,
+        expected: JeCaptureRecord
+    },
+    /// Sent after the end of the capture.
+    Extra {
+        id: i32,
+        data: Vec<u8>
+    }
+}
+
+#[derive(Debug)]
+pub enum JeReplayError {
+    Io(std::io::Error),
+    /// Encryption keys are random per login, so online mode logins can't be played again.
+    Encrypted
+}
+
+impl From<std::io::Error> for JeReplayError {
+    fn from(e: std::io::Error) -> Self {
+        JeReplayError::Io(e)
+    }
+}
+
+/// Send the inbound side of a capture to the server at `addr` and compare what comes back with the outbound side,
+/// waiting up to `wait` for each expected frame. An empty result means the server answered exactly as captured.
+///
+/// Keep-alives are skipped both ways, their ids are timestamps. Record fixtures with an offline mode server.
+pub async fn replay_capture(addr: SocketAddr, records: &[JeCaptureRecord], wait: Duration) -> Result<Vec<JeReplayDiff>, JeReplayError> {
+    let enc_request = JeEncRequest::default().get_packet_id().0;
+    if records.iter().any(|r| r.dir == JeCaptureDirection::Outbound && r.state == ConnectionState::Login && r.id == enc_request) {
+        return Err(JeReplayError::Encrypted);
+    }
+    let protocol = records.iter()
+        .find(|r| r.dir == JeCaptureDirection::Inbound && r.state == ConnectionState::Handshake)
+        .and_then(|r| JePacketHandshake::try_from_raw(&r.data).ok())
+        .and_then(|handshake| JeProtocol::from_number(handshake.protocol_ver.0))
+        .unwrap_or_default();
+    let keep_alive_out = protocol.to_wire(ConnectionState::Play, JeKeepAlive::default().get_packet_id().0, Bytes::new())
+        .map(|(id, _)| id);
+    let keep_alive_in = JeKeepAliveIn::default().get_packet_id().0;
+    let is_keep_alive = |dir: JeCaptureDirection, state: ConnectionState, id: i32| {
+        state == ConnectionState::Play && match dir {
+            JeCaptureDirection::Inbound => protocol.from_wire(state, id) == keep_alive_in,
+            JeCaptureDirection::Outbound => Some(id) == keep_alive_out
+        }
+    };
+    // the codec stays in handshake state, so ids go over the wire untranslated as captured
+    let mut framed = Framed::new(tokio::net::TcpStream::connect(addr).await?, JeCodec::new(JE_MAX_UNCOMPRESSED_LEN));
+    let mut diffs = Vec::new();
+    // state of the latest record, for telling keep-alives apart from other frames
+    let mut state = ConnectionState::Handshake;
+    let set_compression = JeSetCompression::default().get_packet_id().0;
+    for (index, record) in records.iter().enumerate() {
+        state = record.state;
+        if is_keep_alive(record.dir, record.state, record.id) {
+            continue;
+        }
+        if record.dir == JeCaptureDirection::Inbound {
+            if let Err(e) = framed.send(JeFrame::from((record.id, record.data.clone()))).await {
+                debug!("Replay send failed: {:?}", e);
+                break;
+            }
+            continue;
+        }
+        // the frame the server sends instead of this one, past any keep-alives
+        let frame = loop {
+            match tokio::time::timeout(wait, framed.next()).await {
+                Ok(Some(Ok(frame))) if is_keep_alive(record.dir, state, frame.id) => continue,
+                Ok(Some(Ok(frame))) => break Some(frame),
+                _ => break None
+            }
+        };
+        let frame = match frame {
+            Some(frame) => frame,
+            None => {
+                diffs.push(JeReplayDiff::Missing {
+                    index,
+                    expected: record.clone()
+                });
+                break;
+            }
+        };
+        if frame.id != record.id || frame.data[..] != record.data[..] {
+            diffs.push(JeReplayDiff::Changed {
+                index,
+                expected: record.clone(),
+                id: frame.id,
+                data: frame.data.to_vec()
+            });
+        }
+        // the server compresses everything after Set Compression
+        if record.state == ConnectionState::Login && frame.id == set_compression {
+            if let Ok(pk) = JeSetCompression::try_from_raw(&frame.data) {
+                framed.codec_mut().enable_compression(JeCompression {
+                    threshold: pk.threshold.0.max(0) as usize,
+                    level: flate2::Compression::default().level()
+                });
+            }
+        }
+    }
+    if diffs.is_empty() {
+        while let Ok(Some(Ok(frame))) = tokio::time::timeout(wait, framed.next()).await {
+            if is_keep_alive(JeCaptureDirection::Outbound, state, frame.id) {
+                continue;
+            }
+            diffs.push(JeReplayDiff::Extra {
+                id: frame.id,
+                data: frame.data.to_vec()
+            });
+        }
+    }
+    Ok(diffs)
+}
+
+/// Replay the capture at `path` against `addr`, logging every difference.
+pub async fn replay_and_log(addr: SocketAddr, path: PathBuf) {
+    let records = match read_capture(&path) {
+        Ok(records) => records,
+        Err(e) => {
+            warn!("Failed to read capture {:?}: {:?}", &path, e);
+            return;
+        }
+    };
+    match replay_capture(addr, &records, Duration::from_secs(5)).await {
+        Ok(diffs) if diffs.is_empty() => info!("Replay of {:?}: all {} frames match", &path, records.len()),
+        Ok(diffs) => {
+            warn!("Replay of {:?}: {} differences", &path, diffs.len());
+            for diff in diffs {
+                warn!("\t{:?}", diff);
+            }
+        },
+        Err(e) => warn!("Replay of {:?} failed: {:?}", &path, e)
+    }
+}
+
+/// Payloads as hex strings, so fixtures diff well.
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        hex.as_bytes().chunks(2)
+            .map(|pair| std::str::from_utf8(pair).ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| D::Error::custom("bad hex digit")))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_path_stays_in_captures() {
+        let prefix = std::env::temp_dir().join(format!("craftmine-capture-path-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&prefix);
+        std::fs::create_dir_all(prefix.join(JE_CAPTURES_DIR)).unwrap();
+        std::fs::write(prefix.join(JE_CAPTURES_DIR).join("a.jsonl"), "").unwrap();
+        std::fs::write(prefix.join("secret.json"), "").unwrap();
+
+        let capture = capture_path(&prefix, "captures/a.jsonl").unwrap();
+        assert!(capture.ends_with("captures/a.jsonl"));
+        for name in &["secret.json", "captures/../secret.json", "captures", "captures/missing.jsonl"] {
+            assert!(capture_path(&prefix, name).is_err(), "{} should be refused", name);
+        }
+        let absolute = prefix.join("secret.json");
+        assert!(capture_path(&prefix, absolute.to_str().unwrap()).is_err());
+        assert!(capture_path(&prefix, "/etc/passwd").is_err());
+        let _ = std::fs::remove_dir_all(&prefix);
+    }
+}
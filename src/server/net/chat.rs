@@ -0,0 +1,417 @@
+use crate::imports::*;
+use serde::{de::Error as _, Deserializer};
+
+/// Formatting code marker used by vanilla.
+pub const LEGACY_SECTION: char = '§';
+/// Formatting code marker people type in configs and commands.
+pub const LEGACY_AMPERSAND: char = '&';
+
+/// A JSON chat component and its children.
+/// Style left unset is inherited from the parent.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JeChatComponent {
+    #[serde(flatten)]
+    pub content: JeChatContent,
+    #[serde(flatten)]
+    pub style: JeChatStyle,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra: Vec<JeChatComponent>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum JeChatContent {
+    Text {
+        text: String
+    },
+    /// Translation key, resolved by the client, with `%s` arguments.
+    Translate {
+        translate: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        with: Vec<JeChatComponent>
+    },
+    /// Name of whatever the client has bound to a control, e.g. `key.jump`.
+    Keybind {
+        keybind: String
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct JeChatStyle {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<JeChatColor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bold: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub italic: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub underlined: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strikethrough: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub obfuscated: Option<bool>,
+    /// Inserted into the chat box on shift-click.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insertion: Option<String>,
+    #[serde(rename = "clickEvent", skip_serializing_if = "Option::is_none")]
+    pub click_event: Option<JeClickEvent>,
+    #[serde(rename = "hoverEvent", skip_serializing_if = "Option::is_none")]
+    pub hover_event: Option<JeHoverEvent>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JeChatColor {
+    Black,
+    DarkBlue,
+    DarkGreen,
+    DarkAqua,
+    DarkRed,
+    DarkPurple,
+    Gold,
+    Gray,
+    DarkGray,
+    Blue,
+    Green,
+    Aqua,
+    Red,
+    LightPurple,
+    Yellow,
+    White,
+    Reset
+}
+
+impl JeChatColor {
+    /// The color of a legacy code, `0`-`9` and `a`-`f`.
+    pub fn from_legacy_code(code: char) -> Option<JeChatColor> {
+        Some(match code.to_ascii_lowercase() {
+            '0' => JeChatColor::Black,
+            '1' => JeChatColor::DarkBlue,
+            '2' => JeChatColor::DarkGreen,
+            '3' => JeChatColor::DarkAqua,
+            '4' => JeChatColor::DarkRed,
+            '5' => JeChatColor::DarkPurple,
+            '6' => JeChatColor::Gold,
+            '7' => JeChatColor::Gray,
+            '8' => JeChatColor::DarkGray,
+            '9' => JeChatColor::Blue,
+            'a' => JeChatColor::Green,
+            'b' => JeChatColor::Aqua,
+            'c' => JeChatColor::Red,
+            'd' => JeChatColor::LightPurple,
+            'e' => JeChatColor::Yellow,
+            'f' => JeChatColor::White,
+            _ => return None
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JeClickEvent {
+    pub action: JeClickAction,
+    pub value: String
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JeClickAction {
+    OpenUrl,
+    RunCommand,
+    SuggestCommand,
+    ChangePage,
+    CopyToClipboard
+}
+
+impl JeClickEvent {
+    pub fn open_url(url: &str) -> JeClickEvent {
+        Self { action: JeClickAction::OpenUrl, value: url.to_owned() }
+    }
+    pub fn run_command(command: &str) -> JeClickEvent {
+        Self { action: JeClickAction::RunCommand, value: command.to_owned() }
+    }
+    pub fn suggest_command(command: &str) -> JeClickEvent {
+        Self { action: JeClickAction::SuggestCommand, value: command.to_owned() }
+    }
+    pub fn copy_to_clipboard(text: &str) -> JeClickEvent {
+        Self { action: JeClickAction::CopyToClipboard, value: text.to_owned() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JeHoverEvent {
+    pub action: JeHoverAction,
+    pub value: Box<JeChatComponent>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JeHoverAction {
+    ShowText,
+    /// `value` holds the item as SNBT text.
+    ShowItem,
+    /// `value` holds the entity as SNBT text.
+    ShowEntity
+}
+
+impl JeHoverEvent {
+    pub fn show_text(text: JeChatComponent) -> JeHoverEvent {
+        Self { action: JeHoverAction::ShowText, value: Box::new(text) }
+    }
+}
+
+impl Default for JeChatComponent {
+    fn default() -> Self {
+        Self::text("")
+    }
+}
+
+impl JeChatComponent {
+    fn with_content(content: JeChatContent) -> JeChatComponent {
+        Self {
+            content,
+            style: JeChatStyle::default(),
+            extra: Vec::new()
+        }
+    }
+    pub fn text(text: &str) -> JeChatComponent {
+        Self::with_content(JeChatContent::Text { text: text.to_owned() })
+    }
+    pub fn translate(key: &str, with: Vec<JeChatComponent>) -> JeChatComponent {
+        Self::with_content(JeChatContent::Translate { translate: key.to_owned(), with })
+    }
+    pub fn keybind(key: &str) -> JeChatComponent {
+        Self::with_content(JeChatContent::Keybind { keybind: key.to_owned() })
+    }
+    pub fn color(mut self, color: JeChatColor) -> Self {
+        self.style.color = Some(color);
+        self
+    }
+    pub fn bold(mut self, bold: bool) -> Self {
+        self.style.bold = Some(bold);
+        self
+    }
+    pub fn italic(mut self, italic: bool) -> Self {
+        self.style.italic = Some(italic);
+        self
+    }
+    pub fn underlined(mut self, underlined: bool) -> Self {
+        self.style.underlined = Some(underlined);
+        self
+    }
+    pub fn strikethrough(mut self, strikethrough: bool) -> Self {
+        self.style.strikethrough = Some(strikethrough);
+        self
+    }
+    pub fn obfuscated(mut self, obfuscated: bool) -> Self {
+        self.style.obfuscated = Some(obfuscated);
+        self
+    }
+    pub fn insertion(mut self, insertion: &str) -> Self {
+        self.style.insertion = Some(insertion.to_owned());
+        self
+    }
+    pub fn on_click(mut self, event: JeClickEvent) -> Self {
+        self.style.click_event = Some(event);
+        self
+    }
+    pub fn on_hover(mut self, event: JeHoverEvent) -> Self {
+        self.style.hover_event = Some(event);
+        self
+    }
+    /// Add a child, styled like this component unless it says otherwise.
+    pub fn append(mut self, child: JeChatComponent) -> Self {
+        self.extra.push(child);
+        self
+    }
+
+    /// Parse text with legacy formatting codes (e.g. `§cRed §lbold`).
+    /// Any char in `markers` starts a code; one not followed by a valid code is kept as text.
+    /// Like vanilla, a color code clears bold, italic etc.
+    pub fn from_legacy(text: &str, markers: &[char]) -> JeChatComponent {
+        let mut parts = Vec::new();
+        let mut style = JeChatStyle::default();
+        let mut buf = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            let code = match chars.peek() {
+                Some(next) if markers.contains(&c) && is_legacy_code(*next) => next.to_ascii_lowercase(),
+                _ => {
+                    buf.push(c);
+                    continue;
+                }
+            };
+            chars.next();
+            if !buf.is_empty() {
+                let mut part = Self::text(&buf);
+                part.style = style.clone();
+                parts.push(part);
+                buf.clear();
+            }
+            match code {
+                'k' => style.obfuscated = Some(true),
+                'l' => style.bold = Some(true),
+                'm' => style.strikethrough = Some(true),
+                'n' => style.underlined = Some(true),
+                'o' => style.italic = Some(true),
+                'r' => style = JeChatStyle::default(),
+                color => style = JeChatStyle {
+                    color: JeChatColor::from_legacy_code(color),
+                    ..JeChatStyle::default()
+                }
+            }
+        }
+        if !buf.is_empty() {
+            let mut part = Self::text(&buf);
+            part.style = style;
+            parts.push(part);
+        }
+        if parts.len() == 1 {
+            parts.remove(0)
+        } else {
+            let mut root = Self::text("");
+            root.extra = parts;
+            root
+        }
+    }
+    /// Text only, for logs and protocols without formatting.
+    /// Translations show their key.
+    pub fn to_plain(&self) -> String {
+        let mut result = String::new();
+        self.push_plain(&mut result);
+        result
+    }
+    fn push_plain(&self, out: &mut String) {
+        match &self.content {
+            JeChatContent::Text { text } => out.push_str(text),
+            JeChatContent::Translate { translate, .. } => out.push_str(translate),
+            JeChatContent::Keybind { keybind } => out.push_str(keybind)
+        }
+        for child in &self.extra {
+            child.push_plain(out);
+        }
+    }
+    /// Accepts everything vanilla does: objects, bare strings, numbers and arrays.
+    /// An array is its first element with the rest appended as children.
+    pub fn from_value(value: serde_json::Value) -> Result<JeChatComponent, serde_json::Error> {
+        match value {
+            serde_json::Value::String(s) => Ok(Self::text(&s)),
+            serde_json::Value::Number(n) => Ok(Self::text(&n.to_string())),
+            serde_json::Value::Bool(b) => Ok(Self::text(&b.to_string())),
+            serde_json::Value::Array(items) => {
+                let mut items = items.into_iter();
+                let mut first = match items.next() {
+                    Some(first) => Self::from_value(first)?,
+                    None => return Err(serde_json::Error::custom("empty chat component array"))
+                };
+                for item in items {
+                    first.extra.push(Self::from_value(item)?);
+                }
+                Ok(first)
+            },
+            serde_json::Value::Object(_) => {
+                let raw: RawChatComponent = serde_json::from_value(value)?;
+                let content = match (raw.text, raw.translate, raw.keybind) {
+                    (_, Some(translate), _) => JeChatContent::Translate { translate, with: raw.with },
+                    (_, _, Some(keybind)) => JeChatContent::Keybind { keybind },
+                    (text, _, _) => JeChatContent::Text { text: text.unwrap_or_default() }
+                };
+                Ok(Self {
+                    content,
+                    style: raw.style,
+                    extra: raw.extra
+                })
+            },
+            serde_json::Value::Null => Err(serde_json::Error::custom("null chat component"))
+        }
+    }
+}
+
+fn is_legacy_code(c: char) -> bool {
+    JeChatColor::from_legacy_code(c).is_some() || "klmnorKLMNOR".contains(c)
+}
+
+/// Object form of a component, before working out which content it has.
+#[derive(Deserialize)]
+struct RawChatComponent {
+    text: Option<String>,
+    translate: Option<String>,
+    #[serde(default)]
+    with: Vec<JeChatComponent>,
+    keybind: Option<String>,
+    #[serde(flatten)]
+    style: JeChatStyle,
+    #[serde(default)]
+    extra: Vec<JeChatComponent>
+}
+
+impl<'de> Deserialize<'de> for JeChatComponent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        Self::from_value(value).map_err(D::Error::custom)
+    }
+}
+
+impl From<&str> for JeChatComponent {
+    fn from(text: &str) -> Self {
+        Self::text(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(parts: Vec<JeChatComponent>) -> JeChatComponent {
+        let mut root = JeChatComponent::text("");
+        root.extra = parts;
+        root
+    }
+
+    fn legacy(text: &str) -> JeChatComponent {
+        JeChatComponent::from_legacy(text, &[LEGACY_SECTION, LEGACY_AMPERSAND])
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(legacy("Hello"), JeChatComponent::text("Hello"));
+        assert_eq!(legacy(""), JeChatComponent::text(""));
+    }
+
+    #[test]
+    fn colors_and_reset() {
+        assert_eq!(legacy("§cRed"), JeChatComponent::text("Red").color(JeChatColor::Red));
+        // either marker, either case
+        assert_eq!(legacy("&Ahi"), JeChatComponent::text("hi").color(JeChatColor::Green));
+        assert_eq!(legacy("§cRed§rPlain§9Blue"), parts(vec![
+            JeChatComponent::text("Red").color(JeChatColor::Red),
+            JeChatComponent::text("Plain"),
+            JeChatComponent::text("Blue").color(JeChatColor::Blue)
+        ]));
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(legacy("§c§lBold red§oand italic"), parts(vec![
+            JeChatComponent::text("Bold red").color(JeChatColor::Red).bold(true),
+            JeChatComponent::text("and italic").color(JeChatColor::Red).bold(true).italic(true)
+        ]));
+        // a color code clears formatting
+        assert_eq!(legacy("§k§m§nx§ey"), parts(vec![
+            JeChatComponent::text("x").obfuscated(true).strikethrough(true).underlined(true),
+            JeChatComponent::text("y").color(JeChatColor::Yellow)
+        ]));
+    }
+
+    #[test]
+    fn markers_without_codes() {
+        // trailing, unknown code, not a marker, and codes with nothing after them
+        assert_eq!(legacy("100%§"), JeChatComponent::text("100%§"));
+        assert_eq!(legacy("§zfoo & bar"), JeChatComponent::text("§zfoo & bar"));
+        assert_eq!(JeChatComponent::from_legacy("&cno", &[LEGACY_SECTION]), JeChatComponent::text("&cno"));
+        assert_eq!(legacy("§c§l"), JeChatComponent::text(""));
+        assert_eq!(legacy("§§cx"), parts(vec![
+            JeChatComponent::text("§"),
+            JeChatComponent::text("x").color(JeChatColor::Red)
+        ]));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use futures::{SinkExt, StreamExt};
+use openssl::rsa::{Padding, Rsa};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// Headless JE client with just enough of the protocol to drive a server, e.g. over loopback in tests.
+///
+/// Frames aren't translated between versions, so past login only 1.15.2 ids match the declared packets.
+/// Online login doesn't announce itself to a session server, it only gets past the mock one.
+pub struct JeClient {
+    addr: SocketAddr,
+    framed: JeFramed,
+    protocol_ver: i32,
+    pub state: ConnectionState
+}
+
+#[derive(Debug)]
+pub enum JeClientError {
+    Net(JeNetError),
+    Decode(JeDecodeError),
+    /// The server closed the connection.
+    Closed,
+    /// Packet id sent in this state while waiting for something else.
+    Unexpected(ConnectionState, i32),
+    /// Disconnect reason, as plain text.
+    Disconnected(String),
+    Encryption(openssl::error::ErrorStack),
+    BadStatus(serde_json::Error)
+}
+
+impl From<JeNetError> for JeClientError {
+    fn from(e: JeNetError) -> Self {
+        JeClientError::Net(e)
+    }
+}
+
+impl JeClient {
+    /// `protocol_ver` goes in the handshake, it doesn't have to be one we support.
+    pub async fn connect(addr: SocketAddr, protocol_ver: i32) -> std::io::Result<JeClient> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(Self {
+            addr,
+            framed: Framed::new(stream, JeCodec::new(JE_MAX_UNCOMPRESSED_LEN)),
+            protocol_ver,
+            state: ConnectionState::Handshake
+        })
+    }
+    pub async fn send<T: JePacket>(&mut self, packet: T) -> Result<(), JeClientError> {
+        self.framed.send(JeFrame::from((packet.get_packet_id().0, packet.to_vec_u8()))).await?;
+        Ok(())
+    }
+    pub async fn recv(&mut self) -> Result<JeFrame, JeClientError> {
+        match self.framed.next().await {
+            Some(Ok(frame)) => Ok(frame),
+            Some(Err(e)) => Err(JeClientError::Net(e)),
+            None => Err(JeClientError::Closed)
+        }
+    }
+    /// The next packet, which has to be a `T`.
+    pub async fn expect<T: JePacket + Default>(&mut self) -> Result<T, JeClientError> {
+        let frame = self.recv().await?;
+        if frame.id != T::default().get_packet_id().0 {
+            return Err(self.unexpected(&frame));
+        }
+        T::try_from_raw(&frame.data).map_err(JeClientError::Decode)
+    }
+    /// `Disconnected` if `frame` is a Disconnect, `Unexpected` otherwise.
+    fn unexpected(&self, frame: &JeFrame) -> JeClientError {
+        let reason = match self.state {
+            ConnectionState::Login if frame.id == JeLoginDisconnect::default().get_packet_id().0 => {
+                JeLoginDisconnect::try_from_raw(&frame.data).ok().map(|pk| pk.reason)
+            },
+            ConnectionState::Play if frame.id == JePlayDisconnect::default().get_packet_id().0 => {
+                JePlayDisconnect::try_from_raw(&frame.data).ok().map(|pk| pk.reason)
+            },
+            _ => None
+        };
+        match reason {
+            Some(reason) => JeClientError::Disconnected(reason.0.to_plain()),
+            None => JeClientError::Unexpected(self.state, frame.id)
+        }
+    }
+    /// Send a handshake for `next_state`, either status or login.
+    pub async fn handshake(&mut self, next_state: ConnectionState) -> Result<(), JeClientError> {
+        self.send(JePacketHandshake {
+            protocol_ver: JeVarInt(self.protocol_ver),
+            server_addr: self.addr.ip().to_string(),
+            server_port: self.addr.port(),
+            next_state: JeVarInt(if next_state == ConnectionState::Status { 1 } else { 2 })
+        }).await?;
+        self.state = next_state;
+        Ok(())
+    }
+    /// The server list JSON.
+    pub async fn status(&mut self) -> Result<serde_json::Value, JeClientError> {
+        if self.state == ConnectionState::Handshake {
+            self.handshake(ConnectionState::Status).await?;
+        }
+        self.send(JeStatusRequest {}).await?;
+        let response = self.expect::<JeHandshakeResponse>().await?;
+        serde_json::from_str(&response.json).map_err(JeClientError::BadStatus)
+    }
+    /// Returns the value the server echoed.
+    pub async fn ping(&mut self, val: i64) -> Result<i64, JeClientError> {
+        self.send(JePacketPing {
+            val
+        }).await?;
+        Ok(self.expect::<JePacketPong>().await?.val)
+    }
+    /// Log in as `username`, going along with compression, encryption and login plugin requests.
+    pub async fn login(&mut self, username: &str) -> Result<JeLoginSuccess, JeClientError> {
+        if self.state == ConnectionState::Handshake {
+            self.handshake(ConnectionState::Login).await?;
+        }
+        self.send(JeLoginStart {
+            name: username.to_owned()
+        }).await?;
+        loop {
+            let frame = self.recv().await?;
+            match frame.id {
+                // Encryption Request
+                0x01 => {
+                    let request = JeEncRequest::try_from_raw(&frame.data).map_err(JeClientError::Decode)?;
+                    self.encrypt(request).await?;
+                },
+                // Login Success
+                0x02 => {
+                    let success = JeLoginSuccess::try_from_raw(&frame.data).map_err(JeClientError::Decode)?;
+                    self.state = ConnectionState::Play;
+                    return Ok(success);
+                },
+                // Set Compression
+                0x03 => {
+                    let pk = JeSetCompression::try_from_raw(&frame.data).map_err(JeClientError::Decode)?;
+                    self.framed.codec_mut().enable_compression(JeCompression {
+                        threshold: pk.threshold.0.max(0) as usize,
+                        level: flate2::Compression::default().level()
+                    });
+                },
+                // Login Plugin Request, same as vanilla no channels are understood
+                0x04 => {
+                    let request = JeLoginPluginRequest::try_from_raw(&frame.data).map_err(JeClientError::Decode)?;
+                    self.send(JeLoginPluginResponse {
+                        message_id: request.message_id,
+                        successful: false,
+                        data: None
+                    }).await?;
+                },
+                _ => return Err(self.unexpected(&frame))
+            }
+        }
+    }
+    /// Answer an Encryption Request and switch encryption on.
+    async fn encrypt(&mut self, request: JeEncRequest) -> Result<(), JeClientError> {
+        let mut shared_secret = [0u8; 16];
+        openssl::rand::rand_bytes(&mut shared_secret).map_err(JeClientError::Encryption)?;
+        let pubkey = Rsa::public_key_from_der(&request.pubkey).map_err(JeClientError::Encryption)?;
+        let encrypt = |data: &[u8]| -> Result<Vec<u8>, JeClientError> {
+            let mut out = vec![0u8; pubkey.size() as usize];
+            let len = pubkey.public_encrypt(data, &mut out, Padding::PKCS1).map_err(JeClientError::Encryption)?;
+            out.truncate(len);
+            Ok(out)
+        };
+        let response = JeEncResponse {
+            shared_secret: encrypt(&shared_secret)?,
+            vtoken: encrypt(&request.vtoken)?
+        };
+        self.send(response).await?;
+        self.framed.codec_mut().enable_encryption(JeCipher::new(&shared_secret).map_err(JeClientError::Encryption)?);
+        Ok(())
+    }
+    /// Play until the server disconnects, answering keep-alives and ignoring everything else.
+    /// Returns the reason.
+    pub async fn until_disconnect(&mut self) -> Result<String, JeClientError> {
+        let keep_alive = JeKeepAlive::default().get_packet_id().0;
+        loop {
+            let frame = self.recv().await?;
+            if frame.id == keep_alive {
+                let pk = JeKeepAlive::try_from_raw(&frame.data).map_err(JeClientError::Decode)?;
+                self.send(JeKeepAliveIn {
+                    id: pk.id
+                }).await?;
+                continue;
+            }
+            if let JeClientError::Disconnected(reason) = self.unexpected(&frame) {
+                return Ok(reason);
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// A JE client socket, framed into packets.
+pub type JeFramed = Framed<tokio::net::TcpStream, JeCodec>;
+
+/// One packet, without its length prefix.
+#[derive(Debug)]
+pub struct JeFrame {
+    pub id: i32,
+    pub data: Bytes
+}
+
+impl From<(i32, Vec<u8>)> for JeFrame {
+    fn from((id, data): (i32, Vec<u8>)) -> Self {
+        Self {
+            id,
+            data: Bytes::from(data)
+        }
+    }
+}
+
+/// Splits the byte stream into packets and back.
+/// Encryption and compression are switched on during login and apply to every packet after that.
+pub struct JeCodec {
+    max_packet_len: usize,
+    cipher: Option<JeCipher>,
+    compression: Option<JeCompression>,
+    /// Bytes at the front of the read buffer that are already decrypted.
+    decrypted: usize,
+    deflate_buf: Vec<u8>,
+    /// Frames are translated from and to this version's ids and layout.
+    protocol: JeProtocol,
+    state: ConnectionState,
+    /// When the first bytes of a packet not yet complete arrived.
+    partial_since: Option<Instant>,
+    capture: Option<JeCaptureWriter>
+}
+
+impl JeCodec {
+    pub fn new(max_packet_len: usize) -> JeCodec {
+        Self {
+            max_packet_len,
+            cipher: None,
+            compression: None,
+            decrypted: 0,
+            deflate_buf: Vec::new(),
+            protocol: JeProtocol::default(),
+            state: ConnectionState::Handshake,
+            partial_since: None,
+            capture: None
+        }
+    }
+    /// Every byte read or written after this call goes through `cipher`,
+    /// including bytes already buffered but not yet decoded.
+    pub fn enable_encryption(&mut self, cipher: JeCipher) {
+        self.cipher = Some(cipher);
+    }
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+    /// Every packet framed after this call uses the compressed format.
+    pub fn enable_compression(&mut self, compression: JeCompression) {
+        self.compression = Some(compression);
+    }
+    pub fn compression(&self) -> Option<&JeCompression> {
+        self.compression.as_ref()
+    }
+    /// Frames decoded or encoded after this call are translated for `protocol` in `state`.
+    pub fn set_protocol(&mut self, protocol: JeProtocol, state: ConnectionState) {
+        self.protocol = protocol;
+        self.state = state;
+    }
+    /// `None` unless part of a packet is buffered, for catching clients that dribble bytes.
+    pub fn partial_since(&self) -> Option<Instant> {
+        self.partial_since
+    }
+    /// Record every frame decoded or encoded after this call.
+    pub fn set_capture(&mut self, capture: JeCaptureWriter) {
+        self.capture = Some(capture);
+    }
+    /// Stops capturing on the first error rather than failing the connection.
+    fn capture(&mut self, dir: JeCaptureDirection, id: i32, data: &[u8]) {
+        if let Some(capture) = &mut self.capture {
+            if let Err(e) = capture.record(dir, self.state, id, data) {
+                warn!("Failed to write capture {:?}, no longer capturing: {:?}", capture.path(), e);
+                self.capture = None;
+            }
+        }
+    }
+}
+
+/// Decode a VarInt at the start of `buf`.
+/// Returns `Ok(None)` if `buf` ends before the VarInt does.
+pub fn peek_var_int(buf: &[u8]) -> Result<Option<(i32, usize)>, JeNetError> {
+    let mut result: i32 = 0;
+    for (iteration, byte) in buf.iter().take(5).enumerate() {
+        result |= ((byte & 0b0111_1111) as i32) << (7 * iteration);
+        if byte & 0b1000_0000 == 0 {
+            return Ok(Some((result, iteration + 1)));
+        }
+    }
+    if buf.len() >= 5 {
+        Err(JeNetError::VarIntOverflow)
+    } else {
+        Ok(None)
+    }
+}
+
+fn put_var_int(dst: &mut BytesMut, val: i32) {
+    let mut val = val as u32;
+    loop {
+        let temp = (val & 0b0111_1111) as u8;
+        val >>= 7;
+        if val == 0 {
+            dst.put_u8(temp);
+            return;
+        }
+        dst.put_u8(temp | 0b1000_0000);
+    }
+}
+
+fn var_int_len(val: i32) -> usize {
+    let mut val = val as u32;
+    let mut len = 1;
+    while val >= 0b1000_0000 {
+        val >>= 7;
+        len += 1;
+    }
+    len
+}
+
+impl Decoder for JeCodec {
+    type Item = JeFrame;
+    type Error = JeNetError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<JeFrame>, JeNetError> {
+        let frame = self.decode_frame(src)?;
+        self.partial_since = match (&frame, src.is_empty()) {
+            (None, false) => Some(self.partial_since.unwrap_or_else(Instant::now)),
+            _ => None
+        };
+        Ok(frame)
+    }
+}
+
+impl JeCodec {
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<JeFrame>, JeNetError> {
+        if let Some(c) = &mut self.cipher {
+            if src.len() > self.decrypted {
+                c.decrypt_in_place(&mut src[self.decrypted..]);
+                self.decrypted = src.len();
+            }
+        }
+        let (len, len_bytes) = match peek_var_int(&src)? {
+            Some(tup) => tup,
+            None => return Ok(None)
+        };
+        if len < 1 {
+            return Err(JeNetError::BadLength(len));
+        }
+        if len as usize > self.max_packet_len {
+            return Err(JeNetError::TooLarge(len as usize));
+        }
+        let frame_len = len_bytes + len as usize;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+        src.advance(len_bytes);
+        let mut body = src.split_to(len as usize);
+        self.decrypted = self.decrypted.saturating_sub(frame_len);
+
+        if self.compression.is_some() {
+            let (data_len, data_len_bytes) = peek_var_int(&body)?
+                .ok_or(JeNetError::BadLength(len))?;
+            body.advance(data_len_bytes);
+            if data_len != 0 {
+                if data_len < 0 || data_len as usize > JE_MAX_UNCOMPRESSED_LEN {
+                    return Err(JeNetError::BadDataLength(data_len));
+                }
+                let mut inflated = Vec::with_capacity(data_len as usize);
+                flate2::read::ZlibDecoder::new(&body[..])
+                    .take(data_len as u64 + 1)
+                    .read_to_end(&mut inflated)?;
+                if inflated.len() != data_len as usize {
+                    return Err(JeNetError::BadDataLength(data_len));
+                }
+                body = BytesMut::from(&inflated[..]);
+            }
+        }
+
+        let (id, id_bytes) = peek_var_int(&body)?
+            .ok_or(JeNetError::BadLength(len))?;
+        body.advance(id_bytes);
+        self.capture(JeCaptureDirection::Inbound, id, &body);
+        Ok(Some(JeFrame {
+            id: self.protocol.from_wire(self.state, id),
+            data: body.freeze()
+        }))
+    }
+}
+
+impl Encoder<JeFrame> for JeCodec {
+    type Error = JeNetError;
+
+    fn encode(&mut self, item: JeFrame, dst: &mut BytesMut) -> Result<(), JeNetError> {
+        let (id, data) = match self.protocol.to_wire(self.state, item.id, item.data) {
+            Some(wire) => wire,
+            None => {
+                warn!("No {} id for clientbound packet {:#04x}, dropped", self.protocol.name(), item.id);
+                return Ok(());
+            }
+        };
+        self.capture(JeCaptureDirection::Outbound, id, &data);
+        let item = JeFrame {
+            id,
+            data
+        };
+        let start = dst.len();
+        let body_len = var_int_len(item.id) + item.data.len();
+        match &self.compression {
+            Some(c) if body_len >= c.threshold => {
+                self.deflate_buf.clear();
+                let mut encoder = flate2::write::ZlibEncoder::new(
+                    &mut self.deflate_buf,
+                    flate2::Compression::new(c.level)
+                );
+                let mut id_buf = BytesMut::with_capacity(5);
+                put_var_int(&mut id_buf, item.id);
+                encoder.write_all(&id_buf)?;
+                encoder.write_all(&item.data)?;
+                encoder.finish()?;
+                let len = var_int_len(body_len as i32) + self.deflate_buf.len();
+                dst.reserve(5 + len);
+                put_var_int(dst, len as i32);
+                put_var_int(dst, body_len as i32);
+                dst.put_slice(&self.deflate_buf);
+            },
+            Some(_) => {
+                // data length 0 marks an uncompressed packet
+                dst.reserve(5 + 1 + body_len);
+                put_var_int(dst, body_len as i32 + 1);
+                dst.put_u8(0);
+                put_var_int(dst, item.id);
+                dst.put_slice(&item.data);
+            },
+            None => {
+                dst.reserve(5 + body_len);
+                put_var_int(dst, body_len as i32);
+                put_var_int(dst, item.id);
+                dst.put_slice(&item.data);
+            }
+        }
+        if let Some(c) = &mut self.cipher {
+            c.encrypt_in_place(&mut dst[start..]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec(threshold: Option<usize>) -> JeCodec {
+        let mut codec = JeCodec::new(1024);
+        if let Some(threshold) = threshold {
+            codec.enable_compression(JeCompression {
+                threshold,
+                level: 6
+            });
+        }
+        codec
+    }
+
+    fn encode(codec: &mut JeCodec, id: i32, data: &[u8]) -> BytesMut {
+        let mut dst = BytesMut::new();
+        codec.encode(JeFrame::from((id, data.to_vec())), &mut dst).unwrap();
+        dst
+    }
+
+    fn decode_all(codec: &mut JeCodec, src: &mut BytesMut) -> Vec<(i32, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(src).unwrap() {
+            frames.push((frame.id, frame.data.to_vec()));
+        }
+        frames
+    }
+
+    #[test]
+    fn uncompressed_round_trip() {
+        let mut codec = codec(None);
+        let mut src = encode(&mut codec, 0x00, &[1, 2, 3]);
+        assert_eq!(&src[..], &[0x04, 0x00, 1, 2, 3]);
+        src.extend_from_slice(&encode(&mut codec, 0x7f, &[]));
+        assert_eq!(decode_all(&mut codec, &mut src), vec![(0x00, vec![1, 2, 3]), (0x7f, vec![])]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn partial_frames() {
+        let data = vec![0xab; 200];
+        let mut tx = codec(None);
+        let whole = encode(&mut tx, 0x01, &data);
+        let mut rx = codec(None);
+        let mut src = BytesMut::new();
+        for (i, b) in whole.iter().enumerate() {
+            assert!(rx.decode(&mut src).unwrap().is_none());
+            // partial from the first byte on, the two byte length prefix included
+            assert_eq!(rx.partial_since().is_some(), i > 0);
+            src.put_u8(*b);
+        }
+        let frame = rx.decode(&mut src).unwrap().unwrap();
+        assert_eq!((frame.id, frame.data.to_vec()), (0x01, data));
+        assert!(rx.partial_since().is_none());
+    }
+
+    #[test]
+    fn compression_threshold() {
+        let mut codec = codec(Some(16));
+        // 1 byte id and 14 bytes of data is under the threshold, sent with a data length of 0
+        let small = encode(&mut codec, 0x02, &[7; 14]);
+        assert_eq!(&small[..3], &[16, 0x00, 0x02]);
+        assert_eq!(&small[3..], &[7; 14]);
+        // 1 byte id and 15 bytes of data reaches it, the data length is the inflated size
+        let large = encode(&mut codec, 0x02, &[7; 15]);
+        assert_eq!(large[1], 16);
+        assert!(large.len() < 2 + 16);
+        let mut src = small;
+        src.extend_from_slice(&large);
+        assert_eq!(decode_all(&mut codec, &mut src), vec![(0x02, vec![7; 14]), (0x02, vec![7; 15])]);
+    }
+
+    #[test]
+    fn oversize_frames_rejected() {
+        let mut codec = codec(Some(16));
+        // claims one byte more than allowed once inflated
+        let mut src = BytesMut::new();
+        put_var_int(&mut src, 4);
+        put_var_int(&mut src, JE_MAX_UNCOMPRESSED_LEN as i32 + 1);
+        src.put_u8(0x00);
+        match codec.decode(&mut src) {
+            Err(JeNetError::BadDataLength(len)) => assert_eq!(len as usize, JE_MAX_UNCOMPRESSED_LEN + 1),
+            other => panic!("expected BadDataLength, got {:?}", other)
+        }
+        // inflates to less than claimed
+        let mut deflated = Vec::new();
+        let mut encoder = flate2::write::ZlibEncoder::new(&mut deflated, flate2::Compression::default());
+        encoder.write_all(&[0x02; 20]).unwrap();
+        encoder.finish().unwrap();
+        let mut src = BytesMut::new();
+        put_var_int(&mut src, 1 + deflated.len() as i32);
+        put_var_int(&mut src, 21);
+        src.put_slice(&deflated);
+        match codec.decode(&mut src) {
+            Err(JeNetError::BadDataLength(21)) => (),
+            other => panic!("expected BadDataLength, got {:?}", other)
+        }
+        // packet length over the maximum, before any of it arrives
+        let mut src = BytesMut::new();
+        put_var_int(&mut src, 1025);
+        match codec.decode(&mut src) {
+            Err(JeNetError::TooLarge(1025)) => (),
+            other => panic!("expected TooLarge, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let mut tx = codec(Some(4));
+        let mut rx = codec(Some(4));
+        tx.enable_encryption(JeCipher::new(&[9; 16]).unwrap());
+        rx.enable_encryption(JeCipher::new(&[9; 16]).unwrap());
+        let mut src = encode(&mut tx, 0x03, &[1, 2]);
+        src.extend_from_slice(&encode(&mut tx, 0x04, &[5; 32]));
+        assert_ne!(src[2], 0x03);
+        assert_eq!(decode_all(&mut rx, &mut src), vec![(0x03, vec![1, 2]), (0x04, vec![5; 32])]);
+    }
+}
@@ -0,0 +1,38 @@
+use openssl::symm::{Cipher, Crypter, Mode};
+use openssl::error::ErrorStack;
+
+/// AES-128-CFB8 cipher pair for one JE session.
+/// CFB8 works byte by byte, so both halves can be applied to arbitrary chunks of the stream.
+pub struct JeCipher {
+    enc: Crypter,
+    dec: Crypter,
+    scratch: Vec<u8>
+}
+
+impl JeCipher {
+    /// The shared secret is used as both the key and the IV.
+    pub fn new(shared_secret: &[u8]) -> Result<JeCipher, ErrorStack> {
+        let cipher = Cipher::aes_128_cfb8();
+        let mut enc = Crypter::new(cipher, Mode::Encrypt, shared_secret, Some(shared_secret))?;
+        let mut dec = Crypter::new(cipher, Mode::Decrypt, shared_secret, Some(shared_secret))?;
+        enc.pad(false);
+        dec.pad(false);
+        Ok(Self {
+            enc,
+            dec,
+            scratch: Vec::new()
+        })
+    }
+    pub fn encrypt_in_place(&mut self, data: &mut [u8]) {
+        Self::update(&mut self.enc, &mut self.scratch, data);
+    }
+    pub fn decrypt_in_place(&mut self, data: &mut [u8]) {
+        Self::update(&mut self.dec, &mut self.scratch, data);
+    }
+    fn update(crypter: &mut Crypter, scratch: &mut Vec<u8>, data: &mut [u8]) {
+        // openssl wants one block of headroom, CFB8 never uses it
+        scratch.resize(data.len() + 1, 0);
+        let written = crypter.update(data, scratch).unwrap();
+        data.copy_from_slice(&scratch[..written]);
+    }
+}
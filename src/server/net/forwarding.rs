@@ -0,0 +1,186 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use std::net::IpAddr;
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+/// Login plugin channel Velocity answers with the player's details.
+pub const VELOCITY_CHANNEL: &str = "velocity:player_info";
+/// The only layout we read, sent when the request asks for no particular version.
+const VELOCITY_FORWARDING_VERSION: i32 = 1;
+/// HMAC-SHA256 in front of the forwarded data.
+const VELOCITY_SIGNATURE_LEN: usize = 32;
+
+/// The player as a proxy saw them, replacing what the connection itself says.
+#[derive(Debug, Clone)]
+pub struct JeForwardedPlayer {
+    pub ip: IpAddr,
+    pub uuid: Uuid,
+    /// Velocity sends the name, BungeeCord leaves it to Login Start.
+    pub username: Option<String>,
+    pub properties: Vec<JeProfileProperty>
+}
+
+#[derive(Debug)]
+pub enum JeForwardingError {
+    /// Connected without going through the proxy, or the proxy doesn't forward.
+    Missing,
+    BadIp(String),
+    BadUuid(String),
+    BadProperties(serde_json::Error),
+    BadSignature,
+    UnsupportedVersion(i32),
+    Malformed(JeDecodeError)
+}
+
+impl JeForwardedPlayer {
+    /// BungeeCord legacy forwarding, with `server_addr` as
+    /// `host\0ip\0uuid without dashes\0properties as JSON`. The properties are optional.
+    pub fn from_bungee(server_addr: &str) -> Result<JeForwardedPlayer, JeForwardingError> {
+        let mut parts = server_addr.split('\0').skip(1);
+        let (ip, uuid) = match (parts.next(), parts.next()) {
+            (Some(ip), Some(uuid)) => (ip, uuid),
+            _ => return Err(JeForwardingError::Missing)
+        };
+        let properties = match parts.next() {
+            Some(json) => serde_json::from_str(json).map_err(JeForwardingError::BadProperties)?,
+            None => vec![]
+        };
+        Ok(Self {
+            ip: ip.parse().map_err(|_| JeForwardingError::BadIp(ip.to_owned()))?,
+            uuid: Uuid::parse_str(uuid).map_err(|_| JeForwardingError::BadUuid(uuid.to_owned()))?,
+            username: None,
+            properties
+        })
+    }
+    /// Velocity modern forwarding, the data of a Login Plugin Response on `VELOCITY_CHANNEL`.
+    /// Only trusted if signed with `secret`.
+    pub fn from_velocity(secret: &[u8], data: &[u8]) -> Result<JeForwardedPlayer, JeForwardingError> {
+        if data.len() < VELOCITY_SIGNATURE_LEN {
+            return Err(JeForwardingError::BadSignature);
+        }
+        let (signature, signed) = data.split_at(VELOCITY_SIGNATURE_LEN);
+        let expected = PKey::hmac(secret)
+            .and_then(|key| {
+                let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+                signer.update(signed)?;
+                signer.sign_to_vec()
+            })
+            .map_err(|_| JeForwardingError::BadSignature)?;
+        if !openssl::memcmp::eq(signature, &expected) {
+            return Err(JeForwardingError::BadSignature);
+        }
+        let mut offset = 0;
+        let version = read::<JeVarInt>(signed, &mut offset)?.0;
+        if version != VELOCITY_FORWARDING_VERSION {
+            return Err(JeForwardingError::UnsupportedVersion(version));
+        }
+        let ip = read::<String>(signed, &mut offset)?;
+        let uuid = read::<Uuid>(signed, &mut offset)?;
+        let username = read::<String>(signed, &mut offset)?;
+        let count = read::<JeVarInt>(signed, &mut offset)?.as_len().map_err(JeForwardingError::Malformed)?;
+        let mut properties = Vec::with_capacity(count.min(signed.len()));
+        for _ in 0..count {
+            properties.push(JeProfileProperty {
+                name: read(signed, &mut offset)?,
+                value: read(signed, &mut offset)?,
+                signature: read(signed, &mut offset)?
+            });
+        }
+        Ok(Self {
+            ip: ip.parse().map_err(|_| JeForwardingError::BadIp(ip))?,
+            uuid,
+            username: Some(username),
+            properties
+        })
+    }
+}
+
+fn read<T: JeType>(data: &[u8], offset: &mut usize) -> Result<T, JeForwardingError> {
+    let (val, read) = T::try_from_raw(&data[*offset..])
+        .map_err(|e| JeForwardingError::Malformed(e.after(*offset)))?;
+    *offset += read;
+    Ok(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "069a79f444e94726a5befca90e38aaf5";
+
+    #[test]
+    fn bungee_handshake() {
+        let props = r#"[{"name":"textures","value":"dGV4","signature":"c2ln"}]"#;
+        let player = JeForwardedPlayer::from_bungee(&format!("play.example.com\x00203.0.113.7\x00{}\x00{}", UUID, props)).unwrap();
+        assert_eq!(player.ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(player.uuid, Uuid::parse_str(UUID).unwrap());
+        assert!(player.username.is_none());
+        assert_eq!(player.properties.len(), 1);
+        assert_eq!(player.properties[0].name, "textures");
+        assert_eq!(player.properties[0].signature.as_deref(), Some("c2ln"));
+        // properties are optional
+        let player = JeForwardedPlayer::from_bungee(&format!("host\x00::1\x00{}", UUID)).unwrap();
+        assert_eq!(player.ip, "::1".parse::<IpAddr>().unwrap());
+        assert!(player.properties.is_empty());
+    }
+
+    #[test]
+    fn bungee_handshake_missing_fields() {
+        assert!(matches!(JeForwardedPlayer::from_bungee("play.example.com"), Err(JeForwardingError::Missing)));
+        assert!(matches!(JeForwardedPlayer::from_bungee("host\x00203.0.113.7"), Err(JeForwardingError::Missing)));
+        assert!(matches!(
+            JeForwardedPlayer::from_bungee(&format!("host\x00not an ip\x00{}", UUID)),
+            Err(JeForwardingError::BadIp(_))
+        ));
+        assert!(matches!(JeForwardedPlayer::from_bungee("host\x00203.0.113.7\x00nope"), Err(JeForwardingError::BadUuid(_))));
+        assert!(matches!(
+            JeForwardedPlayer::from_bungee(&format!("host\x00203.0.113.7\x00{}\x00{{", UUID)),
+            Err(JeForwardingError::BadProperties(_))
+        ));
+    }
+
+    /// Velocity's player info, signed with `secret`.
+    fn velocity_data(secret: &[u8]) -> Vec<u8> {
+        let mut signed = JeVarInt(VELOCITY_FORWARDING_VERSION).to_vec_u8();
+        signed.extend("203.0.113.7".to_owned().to_vec_u8());
+        signed.extend(Uuid::parse_str(UUID).unwrap().to_vec_u8());
+        signed.extend("Notch".to_owned().to_vec_u8());
+        signed.extend(JeVarInt(1).to_vec_u8());
+        signed.extend("textures".to_owned().to_vec_u8());
+        signed.extend("dGV4".to_owned().to_vec_u8());
+        signed.extend(Some("c2ln".to_owned()).to_vec_u8());
+        let key = PKey::hmac(secret).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(&signed).unwrap();
+        let mut data = signer.sign_to_vec().unwrap();
+        data.extend(signed);
+        data
+    }
+
+    #[test]
+    fn velocity_signed() {
+        let player = JeForwardedPlayer::from_velocity(b"secret", &velocity_data(b"secret")).unwrap();
+        assert_eq!(player.ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(player.uuid, Uuid::parse_str(UUID).unwrap());
+        assert_eq!(player.username.as_deref(), Some("Notch"));
+        assert_eq!(player.properties.len(), 1);
+        assert_eq!(player.properties[0].value, "dGV4");
+        assert_eq!(player.properties[0].signature.as_deref(), Some("c2ln"));
+    }
+
+    #[test]
+    fn velocity_tampered() {
+        let data = velocity_data(b"secret");
+        assert!(matches!(JeForwardedPlayer::from_velocity(b"other", &data), Err(JeForwardingError::BadSignature)));
+        let mut tampered = data.clone();
+        // last byte of the username
+        let at = tampered.len() - 1 - "textures".len() - 1 - "dGV4".len() - 1 - 1 - "c2ln".len() - 1 - 1;
+        assert_eq!(tampered[at], b'h');
+        tampered[at] = b'H';
+        assert!(matches!(JeForwardedPlayer::from_velocity(b"secret", &tampered), Err(JeForwardingError::BadSignature)));
+        let mut bad_signature = data.clone();
+        bad_signature[0] ^= 1;
+        assert!(matches!(JeForwardedPlayer::from_velocity(b"secret", &bad_signature), Err(JeForwardingError::BadSignature)));
+        assert!(matches!(JeForwardedPlayer::from_velocity(b"secret", &data[..16]), Err(JeForwardingError::BadSignature)));
+    }
+}
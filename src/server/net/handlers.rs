@@ -0,0 +1,429 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use std::sync::{Arc, atomic::{AtomicU32, Ordering}};
+
+impl JeRegistries {
+    pub fn new() -> JeRegistries {
+        Self {
+            handshake: JePacketRegistry::new(ConnectionState::Handshake)
+                .register(on_handshake),
+            status: JePacketRegistry::new(ConnectionState::Status)
+                .register(on_status_request)
+                .register(on_ping),
+            login: JePacketRegistry::new(ConnectionState::Login)
+                .register(on_login_start)
+                .register(on_enc_response)
+                .register(on_login_plugin_response),
+            play: JePacketRegistry::new(ConnectionState::Play)
+                .register(on_keep_alive)
+                .fallback(on_play)
+        }
+    }
+}
+
+impl JeSession {
+    /// Enforce the slow packet and login deadlines and idle timeout, and send keep-alives during play.
+    pub async fn check_timers(&mut self) {
+        let now = Instant::now();
+        if let Some(since) = self.framed.codec().partial_since() {
+            if now.duration_since(since) >= Duration::from_secs(self.shared.cc.net.slow_packet_timeout_secs) {
+                info!("{} is sending a packet too slowly, closing", &self.addr);
+                self.shared.throttle.stats.slow_senders.fetch_add(1, Ordering::Relaxed);
+                self.throttle_offense();
+                self.run = false;
+                return;
+            }
+        }
+        if let Some(deadline) = self.login_deadline {
+            if now >= deadline {
+                info!("{} took too long to log in, closing", &self.addr);
+                self.throttle_offense();
+                if self.state == ConnectionState::Login {
+                    self.write(JeLoginDisconnect {
+                        reason: JeChat::text("Took too long to log in")
+                    }).await;
+                }
+                self.run = false;
+            }
+            return;
+        }
+        let timeout = Duration::from_secs(self.shared.cc.net.timeout_secs);
+        let unanswered = match self.pending_keep_alive {
+            Some((_, sent)) => now.duration_since(sent) >= timeout,
+            None => false
+        };
+        if unanswered || now.duration_since(self.last_seen) >= timeout {
+            info!("{} timed out", &self.addr);
+            self.write(JePlayDisconnect {
+                reason: JeChat::text("Timed out")
+            }).await;
+            self.run = false;
+            return;
+        }
+        let interval = Duration::from_secs(self.shared.cc.net.keep_alive_interval_secs);
+        if self.pending_keep_alive.is_none() && now.duration_since(self.last_keep_alive) >= interval {
+            // same as vanilla, the id is a timestamp
+            let id = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or(0);
+            if self.write(JeKeepAlive {
+                id
+            }).await {
+                self.pending_keep_alive = Some((id, now));
+                self.last_keep_alive = now;
+            }
+        }
+    }
+    /// Count an offense against the client's address, unless that is still a forwarding proxy's.
+    fn throttle_offense(&self) {
+        if self.shared.cc.net.forwarding == ProxyForwarding::None || self.conn.is_some() {
+            self.shared.throttle.offense(self.addr.ip());
+        }
+    }
+    /// Apply `max_logins_per_ip`, disconnecting if it's exceeded.
+    /// Returns `false` if the login may not go on.
+    async fn throttle_login(&mut self) -> bool {
+        match self.shared.throttle.on_login(self.addr.ip()) {
+            Ok(()) => true,
+            Err(refusal) => {
+                info!("{} login refused: {:?}", &self.addr, refusal);
+                self.write(JeLoginDisconnect {
+                    reason: JeChat::text(JE_THROTTLED_MESSAGE)
+                }).await;
+                self.run = false;
+                false
+            }
+        }
+    }
+    /// Disconnect or skip, per `kick_invalid_packet`.
+    pub async fn on_invalid_packet(&mut self, state: ConnectionState, id: i32, e: JeDecodeError) {
+        if !self.shared.cc.net.kick_invalid_packet {
+            debug!("DE: @{} packet {:#04x} in {:?} state: {}, skipping", &self.addr, id, state, e);
+            return;
+        }
+        info!("{} sent an invalid packet {:#04x} in {:?} state: {}, closing", &self.addr, id, state, e);
+        let reason = JeChat::text(&format!("Invalid packet: {}", e));
+        match state {
+            ConnectionState::Login => {
+                self.write(JeLoginDisconnect {
+                    reason
+                }).await;
+            },
+            ConnectionState::Play => {
+                self.write(JePlayDisconnect {
+                    reason
+                }).await;
+            },
+            // no way to tell the client why
+            ConnectionState::Handshake | ConnectionState::Status => {}
+        }
+        self.run = false;
+    }
+}
+
+fn on_handshake(session: &mut JeSession, packet: JePacketHandshake) -> JeHandlerFuture<'_> {
+    Box::pin(async move {
+        session.protocol_ver = packet.protocol_ver.0;
+        match ConnectionState::from_next_state(packet.next_state.0) {
+            Some(next) => {
+                session.set_state(next);
+                debug!("{} state -> {:?}", &session.addr, next);
+                if next == ConnectionState::Login && JeProtocol::from_number(session.protocol_ver).is_none() {
+                    info!("{} unsupported protocol {}, closing", &session.addr, session.protocol_ver);
+                    // same wording as vanilla
+                    let reason = if session.protocol_ver < JeProtocol::oldest().number() {
+                        format!("Outdated client! Please use {}", JeProtocol::supported_range())
+                    } else {
+                        format!("Outdated server! I'm still on {}", JeProtocol::supported_range())
+                    };
+                    session.write(JeLoginDisconnect {
+                        reason: JeChat::text(&reason)
+                    }).await;
+                    session.run = false;
+                } else if next == ConnectionState::Login && session.shared.cc.net.forwarding == ProxyForwarding::BungeeCord {
+                    match JeForwardedPlayer::from_bungee(&packet.server_addr) {
+                        Ok(forwarded) => session.forwarded = Some(forwarded),
+                        Err(e) => {
+                            info!("{} BungeeCord forwarding failed: {:?}, closing", &session.addr, e);
+                            // same wording as Spigot
+                            session.write(JeLoginDisconnect {
+                                reason: JeChat::text("If you wish to use IP forwarding, please enable it in your BungeeCord config as well!")
+                            }).await;
+                            session.run = false;
+                        }
+                    }
+                }
+            },
+            None => {
+                debug!("{} invalid next state {}, closing", &session.addr, packet.next_state.0);
+                session.run = false;
+            }
+        }
+    })
+}
+
+fn on_status_request(session: &mut JeSession, _: JeStatusRequest) -> JeHandlerFuture<'_> {
+    Box::pin(async move {
+        debug!("@{} <<< query meta", &session.addr);
+        // lock status
+        let json = {
+            let status_lock = session.shared.server_json_status.read().unwrap();
+            status_lock.to_json(session.protocol_ver)
+        };
+        session.write(JeHandshakeResponse {
+            json
+        }).await;
+    })
+}
+
+fn on_ping(session: &mut JeSession, ping: JePacketPing) -> JeHandlerFuture<'_> {
+    Box::pin(async move {
+        session.write(JePacketPong {
+            val: ping.val
+        }).await;
+    })
+}
+
+fn on_login_start(session: &mut JeSession, pk_login_start: JeLoginStart) -> JeHandlerFuture<'_> {
+    Box::pin(async move {
+        // forwarded logins are counted once the player's own address is known
+        if session.shared.cc.net.forwarding == ProxyForwarding::None && !session.throttle_login().await {
+            return;
+        }
+        if session.shared.cc.net.forwarding == ProxyForwarding::BungeeCord {
+            // checked in the handshake
+            if let Some(forwarded) = session.forwarded.take() {
+                complete_forwarded_login(session, pk_login_start.name, forwarded).await;
+            }
+        } else if session.shared.cc.net.forwarding == ProxyForwarding::Velocity {
+            let mut message_id = [0u8; 4];
+            openssl::rand::rand_bytes(&mut message_id).unwrap();
+            let message_id = i32::from_be_bytes(message_id) & 0x7fffffff;
+            session.write(JeLoginPluginRequest {
+                message_id: JeVarInt(message_id),
+                channel: JeIdentifier::parse(VELOCITY_CHANNEL).unwrap(),
+                data: JeRemainingBytes(vec![])
+            }).await;
+            session.pending_forward = Some((message_id, pk_login_start.name));
+        } else if session.shared.cc.auth.online_mode {
+            let pubkey = session.shared.pubkey_der.clone();
+            let mut vtoken = vec![0u8; 4];
+            openssl::rand::rand_bytes(&mut vtoken).unwrap();
+            // send enc request
+            debug!("Sending enc request");
+            session.write(JeEncRequest {
+                server_id: "".to_owned(),
+                pubkey,
+                vtoken: vtoken.clone()
+            }).await;
+            session.pending_login = Some((pk_login_start.name, vtoken));
+        } else {
+            complete_login(session, pk_login_start.name, None, None).await;
+        }
+    })
+}
+
+fn on_login_plugin_response(session: &mut JeSession, response: JeLoginPluginResponse) -> JeHandlerFuture<'_> {
+    Box::pin(async move {
+        let username = match session.pending_forward.take() {
+            Some((message_id, username)) if message_id == response.message_id.0 => username,
+            pending => {
+                debug!("{} unexpected login plugin response {}", &session.addr, response.message_id.0);
+                session.pending_forward = pending;
+                return;
+            }
+        };
+        // not understanding the channel means no proxy in between
+        let forwarded = match &response.data {
+            Some(data) => JeForwardedPlayer::from_velocity(session.shared.cc.net.velocity_secret.as_bytes(), &data.0),
+            None => Err(JeForwardingError::Missing)
+        };
+        match forwarded {
+            Ok(forwarded) => complete_forwarded_login(session, username, forwarded).await,
+            Err(e) => {
+                info!("{} Velocity forwarding failed: {:?}, closing", &session.addr, e);
+                let reason = match e {
+                    JeForwardingError::Missing => "This server requires you to connect with Velocity.",
+                    _ => "Unable to verify player details."
+                };
+                session.write(JeLoginDisconnect {
+                    reason: JeChat::text(reason)
+                }).await;
+                session.run = false;
+            }
+        }
+    })
+}
+
+/// Take the proxy's word for who the player is and where they connect from.
+async fn complete_forwarded_login(session: &mut JeSession, username: String, forwarded: JeForwardedPlayer) {
+    session.addr = SocketAddr::new(forwarded.ip, session.addr.port());
+    if !session.throttle_login().await {
+        return;
+    }
+    let profile = JeGameProfile {
+        id: forwarded.uuid,
+        name: forwarded.username.unwrap_or(username),
+        properties: forwarded.properties
+    };
+    debug!("{} forwarded as {} ({})", &session.addr, &profile.name, &profile.id);
+    complete_login(session, profile.name.clone(), None, Some(profile)).await;
+}
+
+fn on_enc_response(session: &mut JeSession, enc_response: JeEncResponse) -> JeHandlerFuture<'_> {
+    Box::pin(async move {
+        let (username, vtoken) = match session.pending_login.take() {
+            Some(pending) => pending,
+            None => {
+                debug!("{} unexpected enc response", &session.addr);
+                return;
+            }
+        };
+        let shared = Arc::clone(&session.shared);
+        let shared_secret = match decrypt_enc_response(shared.rsa_keypair, &enc_response, &vtoken) {
+            Ok(shared_secret) => shared_secret,
+            Err(e) => {
+                warn!("{} failed to set up encryption: {:?}", &session.addr, e);
+                session.run = false;
+                return;
+            }
+        };
+        session.framed.codec_mut().enable_encryption(JeCipher::new(&shared_secret).unwrap());
+        debug!("{} encryption enabled", &session.addr);
+        let hash = server_hash("", &shared_secret, shared.pubkey_der);
+        match shared.session_verifier.has_joined(&username, &hash).await {
+            Ok(Some(profile)) => {
+                complete_login(session, username, Some(JeSessionEncrypt {
+                    shared_secret
+                }), Some(profile)).await;
+            },
+            Ok(None) => {
+                info!("{} ({}) failed session verification", &username, &session.addr);
+                session.write(JeLoginDisconnect {
+                    reason: JeChat::text("Failed to verify username!")
+                }).await;
+                session.run = false;
+            },
+            Err(e) => {
+                error!("Session server unreachable while verifying {}: {:?}", &username, e);
+                session.write(JeLoginDisconnect {
+                    reason: JeChat::text("Authentication servers are down. Please try again later.")
+                }).await;
+                session.run = false;
+            }
+        }
+    })
+}
+
+/// Send Login Success and hand the connection to the network thread.
+/// `profile` is from the session server or a proxy.
+async fn complete_login(session: &mut JeSession, username: String, enc: Option<JeSessionEncrypt>, profile: Option<JeGameProfile>) {
+    let shared = Arc::clone(&session.shared);
+    // online players are identified by their profile, offline ones by the prefix
+    let identity = match &profile {
+        Some(profile) => Ok((profile.id, profile.name.clone())),
+        None => shared.sp.users.load_or_new_offline(&username, &shared.cc)
+            .map(|offline_user| (offline_user.uuid, offline_user.username.clone()))
+    };
+    let (uuid, username) = match identity {
+        Ok(identity) => identity,
+        Err(_) => {
+            // failed to get offline user info, shutdown
+            error!("Failed to load or create offline user record, terminating connection");
+            session.run = false;
+            return;
+        }
+    };
+    let refusal = shared.moderation.read().unwrap().refusal(&uuid);
+    if let Some(reason) = refusal {
+        info!("{} ({}) refused: {}", &username, &uuid, &reason);
+        session.write(JeLoginDisconnect {
+            reason: JeChat::legacy(&reason)
+        }).await;
+        session.run = false;
+        return;
+    }
+    if let Some(compression) = JeCompression::from(shared.cc) {
+        if !session.write(JeSetCompression {
+            threshold: JeVarInt(compression.threshold as i32)
+        }).await {
+            return;
+        }
+        session.framed.codec_mut().enable_compression(compression);
+    }
+    if !session.write(JeLoginSuccess {
+        uuid: uuid.to_hyphenated().to_string(),
+        username: username.clone()
+    }).await {
+        return;
+    }
+    session.set_state(ConnectionState::Play);
+    session.login_deadline = None;
+    session.pending_slot = None;
+
+    let online = profile.is_some();
+    let properties = profile.map(|profile| profile.properties).unwrap_or_default();
+    let new_conn = JeConnection {
+        state: session.state,
+        enc,
+        uuid,
+        addr: session.addr,
+        username,
+        outbound: Arc::clone(&session.outbound),
+        online,
+        properties,
+        latency: Arc::new(AtomicU32::new(0))
+    };
+    if shared.send_new_conn.send(new_conn.clone()).is_err() {
+        error!("Network thread gone, closing {}", &session.addr);
+        session.run = false;
+        return;
+    }
+    session.conn = Some(new_conn);
+
+    // TODO: Join Game, Held Item Change, Spawn Position and Player Position And Look
+}
+
+fn on_keep_alive(session: &mut JeSession, keep_alive: JeKeepAliveIn) -> JeHandlerFuture<'_> {
+    Box::pin(async move {
+        match session.pending_keep_alive {
+            Some((id, sent)) if id == keep_alive.id => {
+                let latency = sent.elapsed();
+                if let Some(conn) = &session.conn {
+                    conn.latency.store(latency.as_millis() as u32, Ordering::Relaxed);
+                }
+                debug!("{} latency {:?}", &session.addr, latency);
+                session.pending_keep_alive = None;
+            },
+            _ => {
+                debug!("{} unexpected keep alive {}", &session.addr, keep_alive.id);
+            }
+        }
+    })
+}
+
+/// Play packets are framed only, the game thread decodes them.
+fn on_play<'a>(session: &'a mut JeSession, packet_id: i32, data: &[u8]) -> JeHandlerFuture<'a> {
+    let data = data.to_vec();
+    Box::pin(async move {
+        match &session.conn {
+            Some(conn) => {
+                if session.shared.send_game.send(NetRecvMsg {
+                    uuid: conn.uuid,
+                    inner: NetRecvInner::Packet {
+                        id: packet_id,
+                        data
+                    }
+                }).is_err() {
+                    error!("Game thread gone, closing {}", &session.addr);
+                    session.run = false;
+                }
+            },
+            None => {
+                warn!("{} play packet without a connection", &session.addr);
+                session.run = false;
+            }
+        }
+    })
+}
@@ -1,31 +1,38 @@
-use crate::{server::net::legacy, imports::*, server::symbols::*};
+use crate::server::symbols::*;
 use uuid::Uuid;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::{error::Error, io::{Read, Write, Cursor, Seek, SeekFrom}, convert::TryFrom};
-use futures::future::poll_fn;
-
-#[derive(Debug)]
-pub enum JeValError {
-    OversizedVarInt
-}
 
 #[derive(Debug)]
 pub enum JeNetError {
     IoError(std::io::Error),
     VarIntOverflow,
-    EmptySocket
+    /// Packet length below 1
+    BadLength(i32),
+    /// Packet length above the configured maximum
+    TooLarge(usize),
+    /// Uncompressed length out of range or not matching the inflated data
+    BadDataLength(i32)
+}
+
+impl From<std::io::Error> for JeNetError {
+    fn from(e: std::io::Error) -> Self {
+        JeNetError::IoError(e)
+    }
 }
 
 pub fn server_response_json(
     server_name: &str,
-    server_protocol: u16,
+    server_protocol: i32,
     max_players: u64,
     online_players: u64,
     sample_players: &[(&str, Uuid)],
-    desc: &str,
+    desc: &JeChatComponent,
     favicon: &str,
 ) -> String {
-    let mut result = serde_json::to_string(&serde_json::json!({
+    let sample: Vec<serde_json::Value> = sample_players.iter().map(|(name, uuid)| serde_json::json!({
+        "name": name,
+        "id": uuid.to_hyphenated().to_string()
+    })).collect();
+    let mut result = serde_json::json!({
         "version": {
             "name": server_name,
             "protocol": server_protocol
@@ -33,62 +40,33 @@ pub fn server_response_json(
         "players": {
             "max": max_players,
             "online": online_players,
-            "sample": []
+            "sample": sample
         },
-        "description": {
-            "extra": [
-                {
-                    "text": desc
-                }
-            ],
-            "text": ""
-        },
-        //"favicon": "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg=="
-    }))
-    .unwrap();
-    result
-}
-
-pub async fn read_from_je(stream: &mut tokio::net::TcpStream) -> Result<(usize, i32, Vec<u8>), ()> {
-    let mut poll_test = [0u8; 1];
-    poll_fn(|cx| {
-        stream.poll_peek(cx, &mut poll_test)
-    }).await.map_err(|_| ())?;
-    let mut len_peek = [0u8; 5];
-    let len_peek_read = stream.peek(&mut len_peek).await.map_err(|_| ())?;
-    let (len, len_bytes_read) = match legacy::var_int_to_int(&mut len_peek, len_peek_read) {
-        Ok(tup) => tup,
-        Err(_) => (0, 9999)
-    };
-
-    if len_bytes_read == 9999 {
-        // error parsing varint
-        stream.take(1).read_exact(&mut poll_test).await.map_err(|_| ())?;
-        return Err(())
+        "description": desc
+    });
+    // clients show the default icon when the key is absent
+    if !favicon.is_empty() {
+        result["favicon"] = serde_json::Value::String(favicon.to_owned());
     }
+    serde_json::to_string(&result).unwrap()
+}
 
-    // advance stream by len_bytes_read
-    stream.take(len_bytes_read as u64).read(&mut len_peek).await.map_err(|_| ())?;
-
-    let mut id_peek = [0u8; 5];
-    let id_peek_read = stream.peek(&mut id_peek).await.map_err(|_| ())?;
-    let (id, id_bytes_read) = legacy::var_int_to_int(&mut id_peek, id_peek_read).map_err(|_| ())?;
-
-    // advance stream by id_bytes_read
-    stream.take(id_bytes_read as u64).read(&mut id_peek).await.map_err(|_| ())?;
+/// Largest uncompressed packet a client may announce.
+pub const JE_MAX_UNCOMPRESSED_LEN: usize = 2097152;
 
-    let mut data = Vec::with_capacity(len as usize - id_bytes_read);
-    stream.take(len as u64 - id_bytes_read as u64).read_to_end(&mut data).await.map_err(|_| ())?;
-    Ok((len as usize, id, data))
+/// Settings for packet compression, enabled by Set Compression.
+#[derive(Debug, Clone)]
+pub struct JeCompression {
+    /// Packets with at least this many bytes (id included) are compressed.
+    pub threshold: usize,
+    pub level: u32
 }
 
-pub async fn write_to_je_raw(stream: &mut tokio::net::TcpStream, packet_id: i32, data: &[u8]) -> Result<usize, Box<dyn Error>> {
-    let packet_id_varint = legacy::int_to_var_int(packet_id);
-    let len_msgs: i32 = data.len() as i32;
-    let buf: Vec<u8> = [
-        legacy::int_to_var_int(len_msgs + packet_id_varint.len() as i32),
-        legacy::int_to_var_int(packet_id),
-        data.to_owned()
-    ].iter().flatten().map(|e| *e).collect();
-    stream.write(&buf).await.map_err(|e| e.into())
-}
\ No newline at end of file
+impl JeCompression {
+    pub fn from(cc: &ConfigCollection) -> Option<JeCompression> {
+        cc.net.compression_threshold.map(|threshold| JeCompression {
+            threshold,
+            level: cc.net.compression_level
+        })
+    }
+}
@@ -1,11 +1,10 @@
 use crate::imports::*;
 use crate::server::symbols::*;
-use super::JeValError;
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::error::Error;
 
 /// Convert a `VarInt` to an `i32`.
-/// Returns `(result, VarInt length)` or `JeValError`.
+/// Returns `(result, VarInt length)`.
 /// Peek the stream to get an array, then call this function.
 pub fn var_int_to_int(val: &[u8], read_len: usize) -> Result<(i32, usize), Box<dyn Error>> {
     if val.is_empty() {
@@ -0,0 +1,156 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use tokio::net::TcpStream;
+use std::sync::Arc;
+use crossbeam::sync::ShardedLock;
+
+/// First byte of a pre-1.7 server list ping. Vanilla also assumes no modern client opens with it.
+pub const JE_LEGACY_PING: u8 = 0xfe;
+
+/// How long to wait for the rest of a legacy ping after its first byte.
+const JE_LEGACY_PING_WAIT: Duration = Duration::from_millis(500);
+
+/// Answer a legacy ping if that's what the client opened with.
+/// `received` is anything already read off the stream, e.g. past a PROXY header.
+/// Returns `true` if it was one, in which case the connection is done.
+pub async fn try_legacy_ping(stream: &mut TcpStream, received: &[u8], status: &Arc<ShardedLock<ServerJsonStatus>>) -> std::io::Result<bool> {
+    let mut buf = [0u8; 512];
+    let read = if received.is_empty() {
+        if stream.peek(&mut buf[..1]).await? == 0 || buf[0] != JE_LEGACY_PING {
+            return Ok(false);
+        }
+        0
+    } else {
+        if received[0] != JE_LEGACY_PING {
+            return Ok(false);
+        }
+        let read = received.len().min(buf.len());
+        buf[..read].copy_from_slice(&received[..read]);
+        read
+    };
+    let read = read_ping_start(stream, &mut buf, read, JE_LEGACY_PING_WAIT).await?;
+    // 1.4+ follows with 0x01 (and 1.6 with a plugin message we don't need), beta sends 0xfe alone
+    let beta = read < 2 || buf[1] != 0x01;
+    let kick = {
+        let status = status.read().unwrap();
+        legacy_kick_string(&status, beta)
+    };
+    stream.write_all(&legacy_kick_packet(&kick)).await?;
+    Ok(true)
+}
+
+/// Read into `buf` after the `read` bytes already there until there are two, enough to tell
+/// beta pings from 1.4+ ones, or `wait` passes. Returns how many bytes `buf` holds.
+async fn read_ping_start<R: AsyncRead + Unpin>(stream: &mut R, buf: &mut [u8], mut read: usize, wait: Duration) -> std::io::Result<usize> {
+    let more = async {
+        while read < 2 {
+            match stream.read(&mut buf[read..]).await? {
+                0 => break,
+                n => read += n
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    if let Ok(Err(e)) = tokio::time::timeout(wait, more).await {
+        return Err(e);
+    }
+    Ok(read)
+}
+
+/// 0xff Kick: a length in UTF-16 code units, then the string as UTF-16BE.
+fn legacy_kick_packet(kick: &str) -> Vec<u8> {
+    let utf16: Vec<u16> = kick.encode_utf16().collect();
+    let mut resp = Vec::with_capacity(3 + utf16.len() * 2);
+    resp.push(0xff);
+    resp.extend_from_slice(&(utf16.len() as u16).to_be_bytes());
+    for c in utf16 {
+        resp.extend_from_slice(&c.to_be_bytes());
+    }
+    resp
+}
+
+/// The kick reason legacy clients parse as a status.
+fn legacy_kick_string(status: &ServerJsonStatus, beta: bool) -> String {
+    if beta {
+        // no § allowed in the MOTD here, it's the separator
+        format!("{}§{}§{}", status.desc.to_plain().replace('§', ""), status.online_players, status.max_players)
+    } else {
+        format!(
+            "§1\0{}\0{}\0{}\0{}\0{}",
+            JeProtocol::newest().number(), status.server_name, status.desc.to_plain(), status.online_players, status.max_players
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> ServerJsonStatus {
+        ServerJsonStatus {
+            server_name: "test".to_owned(),
+            online_players: 3,
+            max_players: 20,
+            desc: JeChatComponent::from_legacy("§aA §bserver", &[LEGACY_SECTION]),
+            favicon: String::new(),
+            players: Vec::new(),
+            sample_len: 12,
+            hide_players: false
+        }
+    }
+
+    #[test]
+    fn kick_string() {
+        assert_eq!(
+            legacy_kick_string(&status(), false),
+            format!("§1\0{}\0test\0A server\03\020", JeProtocol::newest().number())
+        );
+        assert_eq!(legacy_kick_string(&status(), true), "A server§3§20");
+    }
+
+    /// Never has anything to read, like a client that stopped after 0xfe.
+    struct Silent;
+
+    impl AsyncRead for Silent {
+        fn poll_read(self: Pin<&mut Self>, _: &mut std::task::Context, _: &mut [u8]) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_second_byte() {
+        let mut buf = [0u8; 512];
+        let mut rest: &[u8] = &[0x01, 0xfa];
+        assert!(read_ping_start(&mut rest, &mut buf, 0, JE_LEGACY_PING_WAIT).await.unwrap() >= 2);
+        // 0xfe arrived on its own, 0x01 after
+        let mut buf = [JE_LEGACY_PING, 0, 0, 0];
+        let mut rest: &[u8] = &[0x01];
+        assert_eq!(read_ping_start(&mut rest, &mut buf, 1, JE_LEGACY_PING_WAIT).await.unwrap(), 2);
+        assert_eq!(buf[1], 0x01);
+        // already there
+        assert_eq!(read_ping_start(&mut Silent, &mut buf, 2, JE_LEGACY_PING_WAIT).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn beta_after_wait() {
+        let mut buf = [JE_LEGACY_PING, 0, 0, 0];
+        let start = Instant::now();
+        assert_eq!(read_ping_start(&mut Silent, &mut buf, 1, Duration::from_millis(50)).await.unwrap(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // or the client closes
+        let mut closed: &[u8] = &[];
+        assert_eq!(read_ping_start(&mut closed, &mut buf, 1, JE_LEGACY_PING_WAIT).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn kick_packet() {
+        // § is U+00A7, one code unit
+        assert_eq!(legacy_kick_packet("§1\0a"), vec![
+            0xff, 0x00, 0x04,
+            0x00, 0xa7, 0x00, b'1', 0x00, 0x00, 0x00, b'a'
+        ]);
+        // outside the BMP is a surrogate pair, two code units
+        assert_eq!(legacy_kick_packet("\u{1f600}"), vec![0xff, 0x00, 0x02, 0xd8, 0x3d, 0xde, 0x00]);
+        assert_eq!(legacy_kick_packet(""), vec![0xff, 0x00, 0x00]);
+    }
+}
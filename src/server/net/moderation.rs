@@ -0,0 +1,91 @@
+use crate::imports::*;
+
+/// Players refused at login, kept by the network thread.
+/// Timeouts are set and lifted by the game, blocks lapse at their deadline.
+#[derive(Default)]
+pub struct JeModeration {
+    /// `None` deadline means until `UnsetTimeout`.
+    timeouts: HashMap<Uuid, (Option<Instant>, String)>,
+    blocks: HashMap<Uuid, (Instant, String)>
+}
+
+impl JeModeration {
+    pub fn set_timeout(&mut self, uuid: Uuid, until: Option<Instant>, reason: String) {
+        self.timeouts.insert(uuid, (until, reason));
+    }
+    pub fn unset_timeout(&mut self, uuid: &Uuid) {
+        self.timeouts.remove(uuid);
+    }
+    pub fn set_block(&mut self, uuid: Uuid, until: Instant, reason: String) {
+        self.blocks.insert(uuid, (until, reason));
+    }
+    pub fn unset_block(&mut self, uuid: &Uuid) {
+        self.blocks.remove(uuid);
+    }
+    /// The reason `uuid` may not join right now, if any.
+    pub fn refusal(&self, uuid: &Uuid) -> Option<String> {
+        let now = Instant::now();
+        if let Some((until, reason)) = self.timeouts.get(uuid) {
+            if !matches!(until, Some(until) if now >= *until) {
+                return Some(reason.to_owned());
+            }
+        }
+        match self.blocks.get(uuid) {
+            Some((until, reason)) if now < *until => Some(reason.to_owned()),
+            _ => None
+        }
+    }
+    /// Drop entries whose deadline has passed.
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        self.timeouts.retain(|_, (until, _)| !matches!(until, Some(until) if now >= *until));
+        self.blocks.retain(|_, (until, _)| now < *until);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_and_unblock() {
+        let mut moderation = JeModeration::default();
+        let player = Uuid::from_u128(1);
+        moderation.set_block(player, Instant::now() + Duration::from_secs(60), "Blocked".to_owned());
+        assert_eq!(moderation.refusal(&player).as_deref(), Some("Blocked"));
+        assert_eq!(moderation.refusal(&Uuid::from_u128(2)), None);
+        moderation.unset_block(&player);
+        assert_eq!(moderation.refusal(&player), None);
+    }
+
+    #[test]
+    fn timeouts_expire() {
+        let mut moderation = JeModeration::default();
+        let timed_out = Uuid::from_u128(1);
+        let forever = Uuid::from_u128(2);
+        let blocked = Uuid::from_u128(3);
+        // deadlines of now have passed by the time they're checked
+        moderation.set_timeout(timed_out, Some(Instant::now()), "Timed out".to_owned());
+        moderation.set_timeout(forever, None, "Timed out for good".to_owned());
+        moderation.set_block(blocked, Instant::now(), "Blocked".to_owned());
+        assert_eq!(moderation.refusal(&timed_out), None);
+        assert_eq!(moderation.refusal(&forever).as_deref(), Some("Timed out for good"));
+        assert_eq!(moderation.refusal(&blocked), None);
+
+        moderation.prune();
+        assert!(!moderation.timeouts.contains_key(&timed_out));
+        assert!(!moderation.blocks.contains_key(&blocked));
+        assert_eq!(moderation.refusal(&forever).as_deref(), Some("Timed out for good"));
+        moderation.unset_timeout(&forever);
+        assert_eq!(moderation.refusal(&forever), None);
+    }
+
+    #[test]
+    fn timeout_outlasts_block() {
+        let mut moderation = JeModeration::default();
+        let player = Uuid::from_u128(1);
+        moderation.set_timeout(player, Some(Instant::now() + Duration::from_secs(60)), "Timed out".to_owned());
+        moderation.set_block(player, Instant::now(), "Blocked".to_owned());
+        assert_eq!(moderation.refusal(&player).as_deref(), Some("Timed out"));
+    }
+}
@@ -11,7 +11,9 @@ pub enum NetSendMsg {
     IndefiniteTimeout(Uuid, String),
     UnsetTimeout(Uuid),
     SetBlock(Uuid, Instant, String),
-    UnsetBlock(Uuid)
+    UnsetBlock(Uuid),
+    /// Play a capture file against this server, logging the differences.
+    Replay(PathBuf)
 }
 
 #[derive(Debug)]
@@ -24,7 +26,8 @@ pub struct NetRecvMsg {
 pub enum NetRecvInner {
     NewSession {
         username: String,
-        online: bool
+        online: bool,
+        properties: Vec<JeProfileProperty>
     },
     EndSession,
     Packet {
@@ -0,0 +1,177 @@
+use crate::server::symbols::*;
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// Packets a client can go without, the ones dropped under `SlowClientPolicy::DropLowPriority`.
+/// Only state that the next packet of the same kind replaces, ids in the 1.15.2 layout.
+const JE_LOW_PRIORITY: &[i32] = &[
+    0x29, // Entity Position
+    0x2a, // Entity Position and Rotation
+    0x2b, // Entity Rotation
+    0x2c, // Entity Movement
+    0x3c, // Entity Head Look
+    0x46, // Entity Velocity
+    0x4f  // Time Update
+];
+
+/// Packets waiting to be written to one player, bounded by their total size.
+/// Shared by the connection task, which writes them, and everyone sending to the player.
+/// The channel itself is unbounded, a packet only goes in once its bytes are reserved
+/// against the budget.
+#[derive(Debug)]
+pub struct JeOutboundQueue {
+    send: UnboundedSender<(i32, Vec<u8>)>,
+    budget: usize,
+    policy: SlowClientPolicy,
+    bytes: AtomicUsize,
+    packets: AtomicUsize,
+    dropped: AtomicU64,
+    /// Set once over budget with nothing to drop. Only the Disconnect is written after that.
+    too_slow: AtomicBool
+}
+
+impl JeOutboundQueue {
+    pub fn new(net: &ConfigNet) -> (Arc<JeOutboundQueue>, UnboundedReceiver<(i32, Vec<u8>)>) {
+        let (send, recv) = tokio::sync::mpsc::unbounded_channel();
+        (Arc::new(Self {
+            send,
+            budget: net.outbound_budget_bytes,
+            policy: net.slow_client_policy,
+            bytes: AtomicUsize::new(0),
+            packets: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            too_slow: AtomicBool::new(false)
+        }), recv)
+    }
+    /// Queue a packet, or apply the slow client policy if that would go over budget.
+    /// Disconnects always fit.
+    pub fn push(&self, packet_id: i32, data: Vec<u8>) {
+        if self.too_slow.load(Ordering::Relaxed) {
+            return;
+        }
+        let disconnect = packet_id == JePlayDisconnect::default().get_packet_id().0;
+        if disconnect {
+            self.bytes.fetch_add(data.len(), Ordering::Relaxed);
+        } else if !self.reserve(data.len()) {
+            if self.policy == SlowClientPolicy::DropLowPriority && JE_LOW_PRIORITY.contains(&packet_id) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            if !self.too_slow.swap(true, Ordering::Relaxed) {
+                let kick = JePlayDisconnect {
+                    reason: JeChat::text("Network too slow")
+                };
+                let data = kick.to_vec_u8();
+                self.bytes.fetch_add(data.len(), Ordering::Relaxed);
+                self.enqueue(kick.get_packet_id().0, data);
+            }
+            return;
+        }
+        self.enqueue(packet_id, data);
+    }
+    /// Take `len` bytes of the budget, unless they don't fit.
+    fn reserve(&self, len: usize) -> bool {
+        self.bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+            queued.checked_add(len).filter(|&total| total <= self.budget)
+        }).is_ok()
+    }
+    /// Send a packet whose bytes are already counted.
+    fn enqueue(&self, packet_id: i32, data: Vec<u8>) {
+        let len = data.len();
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        if self.send.send((packet_id, data)).is_err() {
+            // connection task already gone
+            self.written(len);
+        }
+    }
+    /// Called by the connection task for every packet it takes off the queue, once it's written.
+    pub fn written(&self, len: usize) {
+        self.bytes.fetch_sub(len, Ordering::Relaxed);
+        self.packets.fetch_sub(1, Ordering::Relaxed);
+    }
+    /// Went over budget, everything but the Disconnect is skipped.
+    pub fn is_too_slow(&self) -> bool {
+        self.too_slow.load(Ordering::Relaxed)
+    }
+    pub fn queued_bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+    pub fn queued_packets(&self) -> usize {
+        self.packets.load(Ordering::Relaxed)
+    }
+    /// Low priority packets dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(budget: usize) -> (Arc<JeOutboundQueue>, UnboundedReceiver<(i32, Vec<u8>)>) {
+        JeOutboundQueue::new(&ConfigNet {
+            outbound_budget_bytes: budget,
+            slow_client_policy: SlowClientPolicy::DropLowPriority,
+            ..ConfigNet::default()
+        })
+    }
+
+    #[test]
+    fn budget() {
+        let (queue, mut recv) = queue(10);
+        queue.push(0x0f, vec![0; 6]);
+        queue.push(0x0f, vec![0; 4]);
+        assert_eq!(queue.queued_bytes(), 10);
+        assert_eq!(queue.queued_packets(), 2);
+        let (_, data) = recv.try_recv().unwrap();
+        queue.written(data.len());
+        assert_eq!(queue.queued_bytes(), 4);
+        assert!(!queue.is_too_slow());
+    }
+
+    #[test]
+    fn drops_low_priority() {
+        let (queue, mut recv) = queue(10);
+        queue.push(0x0f, vec![0; 8]);
+        queue.push(0x4f, vec![0; 8]);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.queued_bytes(), 8);
+        assert!(!queue.is_too_slow());
+        assert_eq!(recv.try_recv().unwrap().0, 0x0f);
+        assert!(recv.try_recv().is_err());
+    }
+
+    #[test]
+    fn disconnects_when_over_budget() {
+        let (queue, mut recv) = queue(10);
+        queue.push(0x0f, vec![0; 8]);
+        queue.push(0x0f, vec![0; 8]);
+        assert!(queue.is_too_slow());
+        // nothing more after the Disconnect
+        queue.push(0x0f, vec![0; 1]);
+        assert_eq!(recv.try_recv().unwrap().0, 0x0f);
+        assert_eq!(recv.try_recv().unwrap().0, JePlayDisconnect::default().get_packet_id().0);
+        assert!(recv.try_recv().is_err());
+        assert_eq!(queue.queued_packets(), 2);
+    }
+
+    #[test]
+    fn concurrent_pushes_stay_in_budget() {
+        let (queue, _recv) = queue(1000);
+        let threads: Vec<_> = (0..8).map(|_| {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    queue.push(0x4f, vec![0; 3]);
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(queue.queued_bytes(), 999);
+        assert_eq!(queue.queued_packets(), 333);
+        assert_eq!(queue.dropped(), 800 - 333);
+    }
+}
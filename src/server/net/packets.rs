@@ -1,71 +1,147 @@
-use crate::imports::*;
 use crate::server::symbols::*;
 use async_trait::async_trait;
-use futures::TryFutureExt;
+use futures::{SinkExt, TryFutureExt};
 
 #[async_trait]
 pub trait JePacket: Sized {
     fn get_packet_id(&self) -> JeVarInt;
-    fn try_from_raw(be_bytes: &[u8]) -> Result<Self, ()>;
+    fn try_from_raw(be_bytes: &[u8]) -> Result<Self, JeDecodeError>;
     fn to_vec_u8(&self) -> Vec<u8>;
-    async fn write_to_stream(&self, stream: &mut tokio::net::TcpStream) -> Result<usize, ()> {
-        write_to_je_raw(stream, self.get_packet_id().0, &self.to_vec_u8())
+    async fn write_to_stream(&self, stream: &mut JeFramed) -> Result<(), ()>
+        where Self: Sync {
+        stream.send(JeFrame::from((self.get_packet_id().0, self.to_vec_u8())))
             .map_err(|_| ()).await
     }
 }
 
+/// An encoded packet of any type.
+#[derive(Debug, Clone)]
 pub struct JeGenericPacket {
     pub id: i32,
     pub data: Vec<u8>
 }
 
+/// Walks the fields of a packet as it is decoded.
+pub struct JeDecodeCursor<'a> {
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl<'a> JeDecodeCursor<'a> {
+    pub fn new(bytes: &'a [u8]) -> JeDecodeCursor<'a> {
+        Self { bytes, offset: 0 }
+    }
+    /// Decode the next field with `decode` and move past it.
+    pub fn field<T>(&mut self, packet: &'static str, field: &'static str,
+        decode: impl FnOnce(&[u8]) -> Result<(T, usize), JeDecodeError>) -> Result<T, JeDecodeError> {
+        match decode(&self.bytes[self.offset..]) {
+            Ok((v, bytes_read)) => {
+                debug!("DECODE {:?} OK", std::any::type_name::<T>());
+                self.offset += bytes_read;
+                Ok(v)
+            },
+            Err(e) => {
+                debug!("DECODE {:?} ERR", std::any::type_name::<T>());
+                Err(e.after(self.offset).in_packet(packet, field))
+            }
+        }
+    }
+}
+
 /// Make a struct a packet.
-/// Field types must implement `JeType` and `Default` and end with a `,` in the declaration.
+/// Field types must implement `JeType`, `Default` and `Debug` and end with a `,` in the declaration.
+/// `Vec<T>` fields are prefixed with their length as a VarInt, unless followed by
+/// - `[len = other]`: the element count is in earlier field `other` instead.
+///
+/// `Option<T>` fields are guarded by a Boolean, unless followed by
+/// - `[if other == value]`: present only when earlier field `other` is `value`.
+///
+/// When encoding, keeping `other` consistent is up to the caller.
 macro_rules! declare_packet {
+    (@decode $field_type:ty, $bytes:expr) => {
+        <$field_type>::try_from_raw($bytes)
+    };
+    (@decode $field_type:ty, $bytes:expr, len = $len_field:ident) => {
+        JeLength::as_len(&$len_field)
+            .and_then(|len| <$field_type as JeCountedArray>::read_counted($bytes, len))
+    };
+    (@decode $field_type:ty, $bytes:expr, if $cond_field:ident == $cond_val:expr) => {
+        <$field_type as JeConditional>::read_if($bytes, $cond_field == $cond_val)
+    };
+    (@encode $self:ident, $field_name:ident) => {
+        $self.$field_name.to_vec_u8()
+    };
+    (@encode $self:ident, $field_name:ident, len = $len_field:ident) => {{
+        debug_assert_eq!(JeLength::as_len(&$self.$len_field), Ok($self.$field_name.len()),
+            "{} disagrees with {}", stringify!($len_field), stringify!($field_name));
+        JeCountedArray::write_uncounted(&$self.$field_name)
+    }};
+    (@encode $self:ident, $field_name:ident, if $cond_field:ident == $cond_val:expr) => {{
+        debug_assert_eq!($self.$field_name.is_some(), $self.$cond_field == $cond_val,
+            "{} disagrees with {}", stringify!($cond_field), stringify!($field_name));
+        JeConditional::write_if(&$self.$field_name)
+    }};
+    (@common $packet_id:expr, $name:ident $(, $field_name:ident)*) => {
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.debug_struct(&format!("{} ({:#04x})", stringify!($name), $packet_id))
+                    $(.field(stringify!($field_name), &self.$field_name))*
+                    .finish()
+            }
+        }
+        impl std::convert::From<$name> for JeGenericPacket {
+            fn from(input: $name) -> Self {
+                Self {
+                    id: input.get_packet_id().0,
+                    data: input.to_vec_u8()
+                }
+            }
+        }
+    };
+    ($packet_id:expr, struct $name:ident {}) => {
+        #[derive(Default)]
+        pub struct $name {}
+        impl JePacket for $name {
+            fn get_packet_id(&self) -> JeVarInt {
+                JeVarInt($packet_id)
+            }
+            fn try_from_raw(_: &[u8]) -> Result<Self, JeDecodeError> {
+                Ok(Self {})
+            }
+            fn to_vec_u8(&self) -> Vec<u8> {
+                Vec::new()
+            }
+        }
+        declare_packet!(@common $packet_id, $name);
+    };
     ($packet_id:expr, struct $name:ident {
-        $($field_name:ident: $field_type:ty,)*
+        $($field_name:ident: $field_type:ty $([$($modifier:tt)+])?,)+
     }) => {
         #[derive(Default)]
         pub struct $name {
-            $(pub $field_name: $field_type,)*
+            $(pub $field_name: $field_type,)+
         }
         impl JePacket for $name {
             fn get_packet_id(&self) -> JeVarInt {
                 JeVarInt($packet_id)
             }
-            fn try_from_raw(be_bytes: &[u8]) -> Result<Self, ()> {
-                let mut result = Self::default();
-                let mut counter = 0;
+            fn try_from_raw(be_bytes: &[u8]) -> Result<Self, JeDecodeError> {
+                let mut cursor = JeDecodeCursor::new(be_bytes);
                 $(
-                    match <$field_type>::try_from_raw(be_bytes.split_at(counter).1) {
-                        Ok((v, bytes_read)) => {
-                            debug!("DECODE {:?} OK", std::any::type_name::<$field_type>());
-                            result.$field_name = v;
-                            counter += bytes_read;
-                        },
-                        Err(_) => {
-                            debug!("DECODE {:?} ERR", std::any::type_name::<$field_type>());
-                            return Err(());
-                        }
-                    }
-                )*
-                Ok(result)
+                    let $field_name: $field_type = cursor.field(stringify!($name), stringify!($field_name),
+                        |bytes| declare_packet!(@decode $field_type, bytes $(, $($modifier)+)?))?;
+                )+
+                Ok(Self { $($field_name,)+ })
             }
             fn to_vec_u8(&self) -> Vec<u8> {
                 let mut result = Vec::with_capacity(200);
                 $(
-                    for b in self.$field_name.to_vec_u8() {
-                        result.push(b);
-                    }
-                )*
+                    result.extend(declare_packet!(@encode self, $field_name $(, $($modifier)+)?));
+                )+
                 result
             }
         }
-        impl std::convert::From<$name> for JeGenericPacket {
-            fn from(input: $name) -> Self {
-                unimplemented!()
-            }
-        }
+        declare_packet!(@common $packet_id, $name $(, $field_name)+);
     };
 }
 
@@ -76,7 +152,7 @@ declare_packet!(0x00, struct JePacketHandshake {
     next_state: JeVarInt,
 });
 
-declare_packet!(0x01, struct JeLoginStart {
+declare_packet!(0x00, struct JeLoginStart {
     name: String,
 });
 
@@ -92,6 +168,8 @@ declare_packet!(0x1b, struct JePlayDisconnect {
     reason: JeChat,
 });
 
+declare_packet!(0x00, struct JeStatusRequest {});
+
 declare_packet!(0x01, struct JePacketPing {
     val: i64,
 });
@@ -102,12 +180,31 @@ declare_packet!(0x01, struct JePacketPong {
 
 declare_packet!(0x01, struct JeEncRequest {
     server_id: String,
-    pubkey_len: JeVarInt,
     pubkey: Vec<u8>,
-    vtoken_len: JeVarInt,
     vtoken: Vec<u8>,
 });
 
+declare_packet!(0x01, struct JeEncResponse {
+    shared_secret: Vec<u8>,
+    vtoken: Vec<u8>,
+});
+
+declare_packet!(0x04, struct JeLoginPluginRequest {
+    message_id: JeVarInt,
+    channel: JeIdentifier,
+    data: JeRemainingBytes,
+});
+
+declare_packet!(0x02, struct JeLoginPluginResponse {
+    message_id: JeVarInt,
+    successful: bool,
+    data: Option<JeRemainingBytes> [if successful == true],
+});
+
+declare_packet!(0x03, struct JeSetCompression {
+    threshold: JeVarInt,
+});
+
 declare_packet!(0x02, struct JeLoginSuccess {
     uuid: String,
     username: String,
@@ -126,4 +223,132 @@ declare_packet!(0x26, struct JeJoinGame {
 });
 
 // TODO
-declare_packet!(0x22, struct JeChunk {});
\ No newline at end of file
+declare_packet!(0x22, struct JeChunk {});
+
+declare_packet!(0x21, struct JeKeepAlive {
+    id: i64,
+});
+
+declare_packet!(0x0f, struct JeKeepAliveIn {
+    id: i64,
+});
+
+declare_packet!(0x0f, struct JePlayChatOut {
+    message: JeChat,
+    position: i8,
+});
+
+declare_packet!(0x03, struct JePlayChatIn {
+    message: String,
+});
+
+declare_packet!(0x11, struct JePlayerPosition {
+    x: f64,
+    feet_y: f64,
+    z: f64,
+    on_ground: bool,
+});
+
+declare_packet!(0x12, struct JePlayerPositionRotation {
+    x: f64,
+    feet_y: f64,
+    z: f64,
+    yaw: f32,
+    pitch: f32,
+    on_ground: bool,
+});
+
+declare_packet!(0x13, struct JePlayerRotation {
+    yaw: f32,
+    pitch: f32,
+    on_ground: bool,
+});
+
+declare_packet!(0x14, struct JePlayerMovement {
+    on_ground: bool,
+});
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    declare_packet!(0x7e, struct JeTestCounted {
+        count: JeVarInt,
+        items: Vec<i16> [len = count],
+        tail: bool,
+    });
+
+    declare_packet!(0x7f, struct JeTestConditional {
+        flag: bool,
+        extra: Option<String> [if flag == true],
+        tail: u8,
+    });
+
+    #[test]
+    fn counted_field_round_trips() {
+        let pk = JeTestCounted {
+            count: JeVarInt(2),
+            items: vec![1, -1],
+            tail: true
+        };
+        let data = pk.to_vec_u8();
+        // no length prefix of its own, the count is only in `count`
+        assert_eq!(data, vec![0x02, 0x00, 0x01, 0xff, 0xff, 0x01]);
+        let decoded = JeTestCounted::try_from_raw(&data).unwrap();
+        assert_eq!(decoded.count, JeVarInt(2));
+        assert_eq!(decoded.items, vec![1, -1]);
+        assert!(decoded.tail);
+    }
+
+    #[test]
+    fn counted_field_rejects_bad_count() {
+        assert!(JeTestCounted::try_from_raw(&[0x03, 0x00, 0x01, 0x01]).is_err());
+        // a negative count
+        assert!(JeTestCounted::try_from_raw(&[0xff, 0xff, 0xff, 0xff, 0x0f, 0x01]).is_err());
+    }
+
+    #[test]
+    fn conditional_field_round_trips() {
+        let present = JeTestConditional {
+            flag: true,
+            extra: Some("hi".to_owned()),
+            tail: 7
+        };
+        let data = present.to_vec_u8();
+        assert_eq!(data, vec![0x01, 0x02, b'h', b'i', 0x07]);
+        let decoded = JeTestConditional::try_from_raw(&data).unwrap();
+        assert!(decoded.flag);
+        assert_eq!(decoded.extra.as_deref(), Some("hi"));
+        assert_eq!(decoded.tail, 7);
+
+        let absent = JeTestConditional {
+            flag: false,
+            extra: None,
+            tail: 7
+        };
+        let data = absent.to_vec_u8();
+        // no Boolean guard of its own either
+        assert_eq!(data, vec![0x00, 0x07]);
+        let decoded = JeTestConditional::try_from_raw(&data).unwrap();
+        assert!(!decoded.flag);
+        assert_eq!(decoded.extra, None);
+        assert_eq!(decoded.tail, 7);
+    }
+
+    #[test]
+    fn generic_packet_and_debug() {
+        let pk = JeTestConditional {
+            flag: false,
+            extra: None,
+            tail: 7
+        };
+        assert_eq!(format!("{:?}", pk), "JeTestConditional (0x7f) { flag: false, extra: None, tail: 7 }");
+        let generic = JeGenericPacket::from(pk);
+        assert_eq!(generic.id, 0x7f);
+        assert_eq!(generic.data, vec![0x00, 0x07]);
+
+        assert_eq!(format!("{:?}", JeStatusRequest::default()), "JeStatusRequest (0x00)");
+        let generic = JeGenericPacket::from(JeStatusRequest::default());
+        assert_eq!(generic.id, 0x00);
+        assert!(generic.data.is_empty());
+    }
+}
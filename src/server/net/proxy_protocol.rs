@@ -0,0 +1,261 @@
+use crate::imports::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use bytes::BytesMut;
+
+const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header, CRLF included.
+const PROXY_V1_MAX_LEN: usize = 107;
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_V2_LOCAL: u8 = 0x20;
+const PROXY_V2_PROXY: u8 = 0x21;
+const PROXY_V2_TCP4: u8 = 0x11;
+const PROXY_V2_TCP6: u8 = 0x21;
+/// Balancers send the header straight away, no need to wait for the login timeout.
+pub const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a connection opened with.
+#[derive(Debug, PartialEq)]
+pub enum ProxyHeader {
+    /// No PROXY header, the peer is the client.
+    Absent,
+    /// A header without a client address, e.g. a health check or `UNKNOWN`.
+    Unknown,
+    Source(SocketAddr)
+}
+
+/// Read a HAProxy PROXY protocol v1 or v2 header if the connection starts with one.
+/// Reading stops once the header is complete, anything read past it belongs to the client
+/// and is returned with it.
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<(ProxyHeader, BytesMut)> {
+    let mut buf = BytesMut::with_capacity(PROXY_V1_MAX_LEN);
+    loop {
+        let v1 = PROXY_V1_PREFIX.starts_with(&buf[..buf.len().min(PROXY_V1_PREFIX.len())]);
+        let v2 = PROXY_V2_SIGNATURE.starts_with(&buf[..buf.len().min(PROXY_V2_SIGNATURE.len())]);
+        if !(v1 || v2) {
+            return Ok((ProxyHeader::Absent, buf));
+        }
+        if v1 && buf.len() >= PROXY_V1_PREFIX.len() {
+            if let Some(end) = buf[..buf.len().min(PROXY_V1_MAX_LEN)].windows(2).position(|w| w == b"\r\n") {
+                let line = buf.split_to(end + 2);
+                return parse_v1(&line[..end]).map(|header| (header, buf));
+            }
+            if buf.len() >= PROXY_V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+        }
+        if v2 && buf.len() >= 16 {
+            let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+            if buf.len() >= len {
+                let header = buf.split_to(len);
+                return parse_v2(&header).map(|header| (header, buf));
+            }
+            buf.reserve(len - buf.len());
+        } else {
+            buf.reserve(PROXY_V1_MAX_LEN);
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            if buf.is_empty() {
+                return Ok((ProxyHeader::Absent, buf));
+            }
+            return Err(invalid("closed in PROXY header"));
+        }
+    }
+}
+
+/// A v1 header line, without the CRLF.
+fn parse_v1(line: &[u8]) -> std::io::Result<ProxyHeader> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::Unknown),
+        ["PROXY", "TCP4", src, _, sport, _] | ["PROXY", "TCP6", src, _, sport, _] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad PROXY v1 source address"))?;
+            let port: u16 = sport.parse().map_err(|_| invalid("bad PROXY v1 source port"))?;
+            Ok(ProxyHeader::Source(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("bad PROXY v1 header"))
+    }
+}
+
+/// A whole v2 header, signature to addresses.
+fn parse_v2(header: &[u8]) -> std::io::Result<ProxyHeader> {
+    let addrs = &header[16..];
+    match header[12] {
+        PROXY_V2_LOCAL => return Ok(ProxyHeader::Unknown),
+        PROXY_V2_PROXY => {},
+        _ => return Err(invalid("bad PROXY v2 version or command"))
+    }
+    match header[13] {
+        PROXY_V2_TCP4 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(ProxyHeader::Source(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([addrs[8], addrs[9]]))))
+        },
+        PROXY_V2_TCP6 if addrs.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            Ok(ProxyHeader::Source(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), u16::from_be_bytes([addrs[32], addrs[33]]))))
+        },
+        PROXY_V2_TCP4 | PROXY_V2_TCP6 => Err(invalid("PROXY v2 addresses too short")),
+        // UDP and unix sockets aren't clients
+        _ => Ok(ProxyHeader::Unknown)
+    }
+}
+
+/// Whether `ip` matches an address or CIDR range (`10.0.0.0/8`) in `trusted`.
+pub fn is_trusted_proxy(ip: &IpAddr, trusted: &[String]) -> bool {
+    trusted.iter().any(|entry| {
+        let mut parts = entry.splitn(2, '/');
+        let net: IpAddr = match parts.next().and_then(|net| net.trim().parse().ok()) {
+            Some(net) => net,
+            None => return false
+        };
+        match (ip, net) {
+            (IpAddr::V4(ip), IpAddr::V4(net)) => in_prefix(&ip.octets(), &net.octets(), parts.next()),
+            (IpAddr::V6(ip), IpAddr::V6(net)) => in_prefix(&ip.octets(), &net.octets(), parts.next()),
+            _ => false
+        }
+    })
+}
+
+/// Whether the first `prefix_len` bits match, all of them without a length.
+fn in_prefix(ip: &[u8], net: &[u8], prefix_len: Option<&str>) -> bool {
+    let bits = ip.len() * 8;
+    let prefix_len = match prefix_len.map(|len| len.trim().parse::<usize>()) {
+        Some(Ok(len)) if len <= bits => len,
+        Some(_) => return false,
+        None => bits
+    };
+    ip.iter().zip(net).enumerate().all(|(i, (ip, net))| {
+        let mask_bits = prefix_len.saturating_sub(i * 8).min(8);
+        let mask = if mask_bits == 0 { 0 } else { 0xffu8 << (8 - mask_bits) };
+        ip & mask == net & mask
+    })
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> std::io::Result<(ProxyHeader, BytesMut)> {
+        read_proxy_header(&mut bytes).await
+    }
+
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+        header.extend_from_slice(addrs);
+        header
+    }
+
+    #[tokio::test]
+    async fn absent() {
+        let (header, rest) = read(b"\x10\x00\xf2\x05").await.unwrap();
+        assert_eq!(header, ProxyHeader::Absent);
+        assert_eq!(&rest[..], b"\x10\x00\xf2\x05");
+        // a handshake can start with either signature's first byte, the length
+        let (header, rest) = read(b"\x0d\x00\xf2\x05").await.unwrap();
+        assert_eq!(header, ProxyHeader::Absent);
+        assert_eq!(&rest[..], b"\x0d\x00\xf2\x05");
+        assert_eq!(read(b"").await.unwrap().0, ProxyHeader::Absent);
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (header, rest) = read(b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 25565\r\n\x10\x00").await.unwrap();
+        assert_eq!(header, ProxyHeader::Source("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(&rest[..], b"\x10\x00");
+        let (header, rest) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 56324 25565\r\n").await.unwrap();
+        assert_eq!(header, ProxyHeader::Source("[2001:db8::7]:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap().0, ProxyHeader::Unknown);
+    }
+
+    #[tokio::test]
+    async fn v2_addresses() {
+        let mut addrs = vec![203, 0, 113, 7, 192, 0, 2, 1];
+        addrs.extend_from_slice(&56324u16.to_be_bytes());
+        addrs.extend_from_slice(&25565u16.to_be_bytes());
+        let mut bytes = v2(PROXY_V2_PROXY, PROXY_V2_TCP4, &addrs);
+        bytes.extend_from_slice(b"\x10\x00");
+        let (header, rest) = read(&bytes).await.unwrap();
+        assert_eq!(header, ProxyHeader::Source("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(&rest[..], b"\x10\x00");
+
+        let mut addrs = "2001:db8::7".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addrs.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addrs.extend_from_slice(&56324u16.to_be_bytes());
+        addrs.extend_from_slice(&25565u16.to_be_bytes());
+        let (header, rest) = read(&v2(PROXY_V2_PROXY, PROXY_V2_TCP6, &addrs)).await.unwrap();
+        assert_eq!(header, ProxyHeader::Source("[2001:db8::7]:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+
+        // health checks
+        assert_eq!(read(&v2(PROXY_V2_LOCAL, 0x00, &[])).await.unwrap().0, ProxyHeader::Unknown);
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        assert!(read(b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 25565").await.is_err());
+        assert!(read(b"PROXY").await.is_err());
+        let bytes = v2(PROXY_V2_PROXY, PROXY_V2_TCP4, &[203, 0, 113, 7, 192, 0, 2, 1, 0, 1, 0, 2]);
+        assert!(read(&bytes[..bytes.len() - 1]).await.is_err());
+        assert!(read(&bytes[..14]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed() {
+        assert!(read(b"PROXY TCP4 203.0.113.7 192.0.2.1 56324\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 not.an.ip 192.0.2.1 56324 25565\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 203.0.113.7 192.0.2.1 99999 25565\r\n").await.is_err());
+        assert!(read(b"PROXY \xff\r\n").await.is_err());
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.resize(PROXY_V1_MAX_LEN + 10, b'a');
+        long.extend_from_slice(b"\r\n");
+        assert!(read(&long).await.is_err());
+        // unknown version, and addresses too short for the family
+        assert!(read(&v2(0x31, PROXY_V2_TCP4, &[0; 12])).await.is_err());
+        assert!(read(&v2(PROXY_V2_PROXY, PROXY_V2_TCP4, &[0; 8])).await.is_err());
+        assert!(read(&v2(PROXY_V2_PROXY, PROXY_V2_TCP6, &[0; 12])).await.is_err());
+    }
+
+    #[test]
+    fn trusted_ipv4() {
+        let trusted = vec!["127.0.0.1".to_owned(), "10.0.0.0/8".to_owned(), "192.168.1.128/25".to_owned()];
+        let is = |ip: &str| is_trusted_proxy(&ip.parse().unwrap(), &trusted);
+        assert!(is("127.0.0.1"));
+        assert!(!is("127.0.0.2"));
+        assert!(is("10.255.3.4"));
+        assert!(!is("11.0.0.1"));
+        assert!(is("192.168.1.200"));
+        assert!(!is("192.168.1.100"));
+        // families don't mix
+        assert!(!is("::ffff:127.0.0.1"));
+        assert!(is_trusted_proxy(&"8.8.8.8".parse().unwrap(), &["0.0.0.0/0".to_owned()]));
+    }
+
+    #[test]
+    fn trusted_ipv6() {
+        let trusted = vec!["::1".to_owned(), "2001:db8::/32".to_owned(), "fd00::/7".to_owned()];
+        let is = |ip: &str| is_trusted_proxy(&ip.parse().unwrap(), &trusted);
+        assert!(is("::1"));
+        assert!(is("2001:db8:ffff::1"));
+        assert!(!is("2001:db9::1"));
+        assert!(is("fc12::1"));
+        assert!(!is("fe80::1"));
+        assert!(!is("127.0.0.1"));
+    }
+
+    #[test]
+    fn trusted_bad_entries() {
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!(!is_trusted_proxy(&ip, &["10.0.0.0/33".to_owned()]));
+        assert!(!is_trusted_proxy(&ip, &["10.0.0.0/x".to_owned()]));
+        assert!(!is_trusted_proxy(&ip, &["not an ip".to_owned()]));
+        assert!(!is_trusted_proxy(&ip, &[]));
+    }
+}
@@ -0,0 +1,294 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use std::sync::Arc;
+use crossbeam::sync::ShardedLock;
+use tokio::net::UdpSocket;
+
+const QUERY_MAGIC: [u8; 2] = [0xfe, 0xfd];
+const QUERY_HANDSHAKE: u8 = 9;
+const QUERY_STAT: u8 = 0;
+/// Sent before the key-value section of a full stat. Clients skip it.
+const FULL_STAT_PADDING: [u8; 11] = *b"splitnum\0\x80\0";
+/// Sent before the player list of a full stat.
+const PLAYER_PADDING: [u8; 10] = *b"\x01player_\0\0";
+
+/// GameSpy4 UDP query listener.
+pub struct QueryServer {
+    socket: UdpSocket,
+    responder: QueryResponder
+}
+
+/// Answers query packets, without the socket.
+/// Clients get a challenge token from a handshake and must echo it in stat requests.
+/// Tokens are per address and expire after one or two rotations.
+struct QueryResponder {
+    host_ip: String,
+    host_port: u16,
+    map: String,
+    status: Arc<ShardedLock<ServerJsonStatus>>,
+    sessions: Arc<ShardedLock<HashMap<Uuid, JeConnection>>>,
+    tokens: HashMap<SocketAddr, i32>,
+    old_tokens: HashMap<SocketAddr, i32>
+}
+
+impl QueryServer {
+    pub async fn bind(
+        bind_addr: &str,
+        query_port: u16,
+        je_port: u16,
+        map: &str,
+        status: Arc<ShardedLock<ServerJsonStatus>>,
+        sessions: Arc<ShardedLock<HashMap<Uuid, JeConnection>>>
+    ) -> std::io::Result<QueryServer> {
+        let socket = UdpSocket::bind(format!("{}:{}", bind_addr, query_port)).await?;
+        info!("Query listening on {}", socket.local_addr()?);
+        Ok(Self {
+            socket,
+            responder: QueryResponder {
+                host_ip: bind_addr.to_owned(),
+                host_port: je_port,
+                map: map.to_owned(),
+                status,
+                sessions,
+                tokens: HashMap::new(),
+                old_tokens: HashMap::new()
+            }
+        })
+    }
+    pub async fn run(mut self, token_lifetime: Duration) {
+        let mut rotate = tokio::time::interval(token_lifetime);
+        let mut buf = [0u8; 1460];
+        loop {
+            tokio::select! {
+                recv = self.socket.recv_from(&mut buf) => {
+                    match recv {
+                        Ok((len, addr)) => {
+                            if let Some(resp) = self.responder.respond(&buf[..len], addr) {
+                                if let Err(e) = self.socket.send_to(&resp, &addr).await {
+                                    debug!("Query reply to {} failed: {:?}", &addr, e);
+                                }
+                            }
+                        },
+                        Err(e) => {
+                            warn!("Query socket error: {:?}", e);
+                        }
+                    }
+                }
+                _ = rotate.tick() => {
+                    self.responder.rotate_tokens();
+                }
+            }
+        }
+    }
+}
+
+impl QueryResponder {
+    /// Tokens handed out before the last rotation stop working.
+    fn rotate_tokens(&mut self) {
+        self.old_tokens = std::mem::replace(&mut self.tokens, HashMap::new());
+    }
+    fn respond(&mut self, req: &[u8], addr: SocketAddr) -> Option<Vec<u8>> {
+        if req.len() < 7 || req[..2] != QUERY_MAGIC {
+            return None;
+        }
+        let session_id = i32::from_be_bytes([req[3], req[4], req[5], req[6]]) & 0x0f0f0f0f;
+        let mut resp = Vec::with_capacity(256);
+        resp.push(req[2]);
+        resp.extend_from_slice(&session_id.to_be_bytes());
+        match req[2] {
+            QUERY_HANDSHAKE => {
+                let token = self.new_token(addr);
+                push_str(&mut resp, &token.to_string());
+            },
+            QUERY_STAT if req.len() >= 11 => {
+                let token = i32::from_be_bytes([req[7], req[8], req[9], req[10]]);
+                if !self.is_valid_token(&addr, token) {
+                    debug!("Query from {} with bad token", &addr);
+                    return None;
+                }
+                // full stat requests are padded to 15 bytes
+                if req.len() >= 15 {
+                    self.full_stat(&mut resp);
+                } else {
+                    self.basic_stat(&mut resp);
+                }
+            },
+            _ => return None
+        }
+        Some(resp)
+    }
+    fn new_token(&mut self, addr: SocketAddr) -> i32 {
+        let mut bytes = [0u8; 4];
+        openssl::rand::rand_bytes(&mut bytes).unwrap();
+        let token = i32::from_be_bytes(bytes) & 0x7fffffff;
+        self.tokens.insert(addr, token);
+        token
+    }
+    fn is_valid_token(&self, addr: &SocketAddr, token: i32) -> bool {
+        self.tokens.get(addr) == Some(&token) || self.old_tokens.get(addr) == Some(&token)
+    }
+    fn basic_stat(&self, resp: &mut Vec<u8>) {
+        let status = self.status.read().unwrap();
+        push_str(resp, &status.desc.to_plain());
+        push_str(resp, "SMP");
+        push_str(resp, &self.map);
+        push_str(resp, &status.online_players.to_string());
+        push_str(resp, &status.max_players.to_string());
+        // the only little endian field
+        resp.extend_from_slice(&self.host_port.to_le_bytes());
+        push_str(resp, &self.host_ip);
+    }
+    fn full_stat(&self, resp: &mut Vec<u8>) {
+        resp.extend_from_slice(&FULL_STAT_PADDING);
+        {
+            let status = self.status.read().unwrap();
+            for (k, v) in &[
+                ("hostname", status.desc.to_plain()),
+                ("gametype", "SMP".to_owned()),
+                ("game_id", "MINECRAFT".to_owned()),
+                ("version", status.server_name.to_owned()),
+                ("plugins", format!("CraftMine {}", crate::SERVER_RELNAME)),
+                ("map", self.map.to_owned()),
+                ("numplayers", status.online_players.to_string()),
+                ("maxplayers", status.max_players.to_string()),
+                ("hostport", self.host_port.to_string()),
+                ("hostip", self.host_ip.to_owned())
+            ] {
+                push_str(resp, k);
+                push_str(resp, v);
+            }
+        }
+        resp.push(0);
+        resp.extend_from_slice(&PLAYER_PADDING);
+        for conn in self.sessions.read().unwrap().values() {
+            push_str(resp, &conn.username);
+        }
+        resp.push(0);
+    }
+}
+
+/// Null-terminated string.
+fn push_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    const CLIENT: ([u8; 4], u16) = ([127, 0, 0, 1], 50000);
+
+    fn responder() -> QueryResponder {
+        let status = ServerJsonStatus {
+            server_name: "test".to_owned(),
+            online_players: 1,
+            max_players: 20,
+            desc: JeChatComponent::text("A server"),
+            favicon: String::new(),
+            players: Vec::new(),
+            sample_len: 12,
+            hide_players: false
+        };
+        let mut sessions = HashMap::new();
+        sessions.insert(Uuid::nil(), JeConnection {
+            state: ConnectionState::Play,
+            enc: None,
+            uuid: Uuid::nil(),
+            username: "Tester".to_owned(),
+            addr: SocketAddr::from(CLIENT),
+            outbound: JeOutboundQueue::new(&ConfigNet::default()).0,
+            online: false,
+            properties: Vec::new(),
+            latency: Arc::new(AtomicU32::new(0))
+        });
+        QueryResponder {
+            host_ip: "127.0.0.1".to_owned(),
+            host_port: 25565,
+            map: "world".to_owned(),
+            status: Arc::new(ShardedLock::new(status)),
+            sessions: Arc::new(ShardedLock::new(sessions)),
+            tokens: HashMap::new(),
+            old_tokens: HashMap::new()
+        }
+    }
+
+    fn request(kind: u8, token: Option<i32>, full: bool) -> Vec<u8> {
+        let mut req = vec![0xfe, 0xfd, kind, 0x00, 0x00, 0x00, 0x01];
+        if let Some(token) = token {
+            req.extend_from_slice(&token.to_be_bytes());
+        }
+        if full {
+            req.extend_from_slice(&[0; 4]);
+        }
+        req
+    }
+
+    /// Handshake from `CLIENT` and return the token.
+    fn handshake(responder: &mut QueryResponder) -> i32 {
+        let resp = responder.respond(&request(QUERY_HANDSHAKE, None, false), SocketAddr::from(CLIENT)).unwrap();
+        assert_eq!(&resp[..5], &[QUERY_HANDSHAKE, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(resp.last(), Some(&0));
+        std::str::from_utf8(&resp[5..resp.len() - 1]).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn basic_stat() {
+        let mut responder = responder();
+        let token = handshake(&mut responder);
+        let resp = responder.respond(&request(QUERY_STAT, Some(token), false), SocketAddr::from(CLIENT)).unwrap();
+        let mut expected = vec![QUERY_STAT, 0x00, 0x00, 0x00, 0x01];
+        expected.extend_from_slice(b"A server\0SMP\0world\x001\x0020\0");
+        expected.extend_from_slice(&[0xdd, 0x63]);
+        expected.extend_from_slice(b"127.0.0.1\0");
+        assert_eq!(resp, expected);
+    }
+
+    #[test]
+    fn full_stat() {
+        let mut responder = responder();
+        let token = handshake(&mut responder);
+        let resp = responder.respond(&request(QUERY_STAT, Some(token), true), SocketAddr::from(CLIENT)).unwrap();
+        let mut expected = vec![QUERY_STAT, 0x00, 0x00, 0x00, 0x01];
+        expected.extend_from_slice(&FULL_STAT_PADDING);
+        for (k, v) in &[
+            ("hostname", "A server".to_owned()),
+            ("gametype", "SMP".to_owned()),
+            ("game_id", "MINECRAFT".to_owned()),
+            ("version", "test".to_owned()),
+            ("plugins", format!("CraftMine {}", crate::SERVER_RELNAME)),
+            ("map", "world".to_owned()),
+            ("numplayers", "1".to_owned()),
+            ("maxplayers", "20".to_owned()),
+            ("hostport", "25565".to_owned()),
+            ("hostip", "127.0.0.1".to_owned())
+        ] {
+            push_str(&mut expected, k);
+            push_str(&mut expected, v);
+        }
+        expected.push(0);
+        expected.extend_from_slice(&PLAYER_PADDING);
+        expected.extend_from_slice(b"Tester\0\0");
+        assert_eq!(resp, expected);
+    }
+
+    #[test]
+    fn tokens() {
+        let mut responder = responder();
+        let other = SocketAddr::from(([127, 0, 0, 2], 50000));
+        // no handshake yet, wrong token, and a token from another address
+        assert!(responder.respond(&request(QUERY_STAT, Some(0), false), SocketAddr::from(CLIENT)).is_none());
+        let token = handshake(&mut responder);
+        assert!(responder.respond(&request(QUERY_STAT, Some(token ^ 1), false), SocketAddr::from(CLIENT)).is_none());
+        assert!(responder.respond(&request(QUERY_STAT, Some(token), false), other).is_none());
+        // good for one rotation, gone after two
+        responder.rotate_tokens();
+        assert!(responder.respond(&request(QUERY_STAT, Some(token), false), SocketAddr::from(CLIENT)).is_some());
+        responder.rotate_tokens();
+        assert!(responder.respond(&request(QUERY_STAT, Some(token), false), SocketAddr::from(CLIENT)).is_none());
+        // not a query, or cut short
+        assert!(responder.respond(&[0xfe, 0xfd, QUERY_HANDSHAKE], SocketAddr::from(CLIENT)).is_none());
+        assert!(responder.respond(&[0x00; 7], SocketAddr::from(CLIENT)).is_none());
+    }
+}
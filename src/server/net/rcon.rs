@@ -0,0 +1,353 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use std::{net::IpAddr, sync::{Arc, Mutex}};
+use tokio::net::{TcpListener, TcpStream};
+
+const RCON_RESPONSE_VALUE: i32 = 0;
+const RCON_EXEC_COMMAND: i32 = 2;
+const RCON_AUTH_RESPONSE: i32 = 2;
+const RCON_AUTH: i32 = 3;
+/// Longest body clients send, per the Source spec.
+const RCON_MAX_REQUEST_BODY: usize = 1446;
+/// Longer output is split over several response packets.
+const RCON_MAX_RESPONSE_BODY: usize = 4096;
+/// Commands taking longer than this get an empty response.
+const RCON_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+struct RconPacket {
+    id: i32,
+    kind: i32,
+    body: String
+}
+
+#[derive(Debug, PartialEq)]
+enum RconAuth {
+    Ok,
+    Failed,
+    /// Failed, and that was one too many for this address.
+    LockedOut
+}
+
+/// Failed logins from one address.
+struct RconFailures {
+    count: u32,
+    locked_until: Option<Instant>
+}
+
+/// Source RCON listener. Authenticated clients' commands go to the game thread like console input.
+pub struct RconServer {
+    listener: TcpListener,
+    shared: Arc<RconShared>
+}
+
+struct RconShared {
+    password: String,
+    cli_send: crossbeam::Sender<CliCommand>,
+    max_failures: u32,
+    lockout: Duration,
+    failures: Mutex<HashMap<IpAddr, RconFailures>>
+}
+
+impl RconServer {
+    pub async fn bind(bind_addr: &str, cc: &ConfigCollection, rcon_port: u16, cli_send: crossbeam::Sender<CliCommand>) -> std::io::Result<RconServer> {
+        let listener = TcpListener::bind(format!("{}:{}", bind_addr, rcon_port)).await?;
+        info!("RCON listening on {}", listener.local_addr()?);
+        Ok(Self {
+            listener,
+            shared: Arc::new(RconShared {
+                password: cc.net.rcon_password.to_owned(),
+                cli_send,
+                max_failures: cc.net.rcon_max_auth_failures,
+                lockout: Duration::from_secs(cc.net.rcon_lockout_secs),
+                failures: Mutex::new(HashMap::new())
+            })
+        })
+    }
+    pub async fn run(mut self) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, addr)) => {
+                    if self.shared.is_locked_out(&addr.ip()) {
+                        debug!("RCON: {} is locked out", &addr);
+                        continue;
+                    }
+                    tokio::task::spawn(rcon_client(stream, addr, Arc::clone(&self.shared)));
+                },
+                Err(e) => {
+                    warn!("RCON: accept failed: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+impl RconShared {
+    fn is_locked_out(&self, ip: &IpAddr) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(ip).and_then(|f| f.locked_until) {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                failures.remove(ip);
+                false
+            },
+            None => false
+        }
+    }
+    /// Check `password`, counting failures against `ip`.
+    fn authenticate(&self, ip: IpAddr, password: &str) -> RconAuth {
+        // an empty password disables RCON logins altogether
+        if !self.password.is_empty() && password == self.password {
+            self.failures.lock().unwrap().remove(&ip);
+            RconAuth::Ok
+        } else if self.record_failure(ip) {
+            RconAuth::LockedOut
+        } else {
+            RconAuth::Failed
+        }
+    }
+    /// Returns `true` if this failure locked the address out.
+    fn record_failure(&self, ip: IpAddr) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures.entry(ip).or_insert(RconFailures {
+            count: 0,
+            locked_until: None
+        });
+        entry.count += 1;
+        if entry.count >= self.max_failures {
+            entry.locked_until = Some(Instant::now() + self.lockout);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+async fn rcon_client(mut stream: TcpStream, addr: SocketAddr, shared: Arc<RconShared>) {
+    let mut authed = false;
+    loop {
+        let packet = match read_packet(&mut stream).await {
+            Ok(Some(packet)) => packet,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("RCON: {} bad packet {:?}, closing", &addr, e);
+                break;
+            }
+        };
+        let result = match (packet.kind, authed) {
+            (RCON_AUTH, _) => match shared.authenticate(addr.ip(), &packet.body) {
+                RconAuth::Ok => {
+                    authed = true;
+                    info!("RCON: {} logged in", &addr);
+                    write_packet(&mut stream, packet.id, RCON_AUTH_RESPONSE, "").await
+                },
+                RconAuth::Failed => {
+                    warn!("RCON: {} failed to log in", &addr);
+                    write_packet(&mut stream, -1, RCON_AUTH_RESPONSE, "").await
+                },
+                RconAuth::LockedOut => {
+                    warn!("RCON: {} failed to log in, locking out {} for {:?}", &addr, addr.ip(), shared.lockout);
+                    // closing anyway
+                    let _ = write_packet(&mut stream, -1, RCON_AUTH_RESPONSE, "").await;
+                    break;
+                }
+            },
+            (RCON_EXEC_COMMAND, true) => {
+                info!("RCON: {} issued: {}", &addr, &packet.body);
+                let output = run_command(&shared, packet.body).await;
+                write_response(&mut stream, packet.id, &output).await
+            },
+            // clients send an empty response value after a command to find the end of a split response
+            (RCON_RESPONSE_VALUE, true) => write_packet(&mut stream, packet.id, RCON_RESPONSE_VALUE, "").await,
+            (_, false) => {
+                debug!("RCON: {} sent a command before logging in, closing", &addr);
+                break;
+            },
+            (kind, true) => {
+                debug!("RCON: {} unknown packet type {}", &addr, kind);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            debug!("RCON: {} write failed {:?}, closing", &addr, e);
+            break;
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+/// Hand the command to the game thread and wait for its output.
+async fn run_command(shared: &RconShared, line: String) -> String {
+    let (reply, recv_reply) = crossbeam::bounded(1);
+    if shared.cli_send.send(CliCommand { line, reply }).is_err() {
+        return "Server is shutting down".to_owned();
+    }
+    tokio::task::spawn_blocking(move || recv_reply.recv_timeout(RCON_COMMAND_TIMEOUT))
+        .await
+        .ok()
+        .and_then(|output| output.ok())
+        .unwrap_or_default()
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<Option<RconPacket>> {
+    let mut len_buf = [0u8; 4];
+    match stream.read_exact(&mut len_buf).await {
+        Ok(_) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    }
+    let len = i32::from_le_bytes(len_buf);
+    // id, type and two nulls
+    if len < 10 || len as usize > 10 + RCON_MAX_REQUEST_BODY {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad length"));
+    }
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    let id = i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let kind = i32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    let body = &buf[8..buf.len() - 2];
+    let body = match body.iter().position(|b| *b == 0) {
+        Some(end) => &body[..end],
+        None => body
+    };
+    Ok(Some(RconPacket {
+        id,
+        kind,
+        body: String::from_utf8_lossy(body).into_owned()
+    }))
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(stream: &mut W, id: i32, kind: i32, body: &str) -> std::io::Result<()> {
+    stream.write_all(&encode_packet(id, kind, body)).await
+}
+
+/// Little endian length, id and type, then the body and two nulls.
+fn encode_packet(id: i32, kind: i32, body: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(14 + body.len());
+    buf.extend_from_slice(&(10 + body.len() as i32).to_le_bytes());
+    buf.extend_from_slice(&id.to_le_bytes());
+    buf.extend_from_slice(&kind.to_le_bytes());
+    buf.extend_from_slice(body.as_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    buf
+}
+
+/// Split `output` over as many packets as needed, without breaking characters.
+async fn write_response<W: AsyncWrite + Unpin>(stream: &mut W, id: i32, output: &str) -> std::io::Result<()> {
+    let mut rest = output;
+    loop {
+        let mut split = rest.len().min(RCON_MAX_RESPONSE_BODY);
+        while !rest.is_char_boundary(split) {
+            split -= 1;
+        }
+        let (chunk, tail) = rest.split_at(split);
+        write_packet(stream, id, RCON_RESPONSE_VALUE, chunk).await?;
+        if tail.is_empty() {
+            return Ok(());
+        }
+        rest = tail;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn shared(password: &str, max_failures: u32) -> RconShared {
+        RconShared {
+            password: password.to_owned(),
+            cli_send: crossbeam::unbounded().0,
+            max_failures,
+            lockout: Duration::from_secs(60),
+            failures: Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn read_all(mut bytes: &[u8]) -> Vec<RconPacket> {
+        let mut packets = Vec::new();
+        while let Some(packet) = read_packet(&mut bytes).await.unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(encode_packet(7, RCON_AUTH, "pw"), vec![
+            12, 0, 0, 0,
+            7, 0, 0, 0,
+            3, 0, 0, 0,
+            b'p', b'w', 0, 0
+        ]);
+        assert_eq!(encode_packet(-1, RCON_AUTH_RESPONSE, ""), vec![
+            10, 0, 0, 0,
+            0xff, 0xff, 0xff, 0xff,
+            2, 0, 0, 0,
+            0, 0
+        ]);
+    }
+
+    #[tokio::test]
+    async fn decode() {
+        let mut bytes = encode_packet(1, RCON_EXEC_COMMAND, "list");
+        bytes.extend_from_slice(&encode_packet(2, RCON_RESPONSE_VALUE, ""));
+        assert_eq!(read_all(&bytes).await, vec![
+            RconPacket {
+                id: 1,
+                kind: RCON_EXEC_COMMAND,
+                body: "list".to_owned()
+            },
+            RconPacket {
+                id: 2,
+                kind: RCON_RESPONSE_VALUE,
+                body: String::new()
+            }
+        ]);
+        // lengths too short or too long, and a packet cut short
+        for len in &[9i32, 10 + RCON_MAX_REQUEST_BODY as i32 + 1] {
+            let mut bytes = len.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&[0; 10]);
+            assert!(read_packet(&mut &bytes[..]).await.is_err());
+        }
+        let bytes = encode_packet(1, RCON_EXEC_COMMAND, "list");
+        assert!(read_packet(&mut &bytes[..bytes.len() - 1]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn split_response() {
+        // 2 byte characters straddling the 4096 byte mark stay whole
+        let output = format!("a{}", "é".repeat(RCON_MAX_RESPONSE_BODY));
+        let mut bytes = Vec::new();
+        write_response(&mut bytes, 5, &output).await.unwrap();
+        // longer than read_packet takes from clients, so split by hand
+        let mut bodies = Vec::new();
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let len = i32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            assert_eq!(&rest[4..12], &encode_packet(5, RCON_RESPONSE_VALUE, "")[4..12]);
+            assert_eq!(&rest[2 + len..4 + len], &[0, 0]);
+            bodies.push(std::str::from_utf8(&rest[12..2 + len]).unwrap());
+            rest = &rest[4 + len..];
+        }
+        assert_eq!(bodies.iter().map(|b| b.len()).collect::<Vec<_>>(), vec![RCON_MAX_RESPONSE_BODY - 1, RCON_MAX_RESPONSE_BODY, 2]);
+        assert_eq!(bodies.concat(), output);
+    }
+
+    #[test]
+    fn auth() {
+        let ip = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::from(Ipv4Addr::new(127, 0, 0, 2));
+        let rcon = shared("secret", 3);
+        assert_eq!(rcon.authenticate(ip, "wrong"), RconAuth::Failed);
+        // logging in clears the count
+        assert_eq!(rcon.authenticate(ip, "secret"), RconAuth::Ok);
+        assert_eq!(rcon.authenticate(ip, "wrong"), RconAuth::Failed);
+        assert_eq!(rcon.authenticate(ip, "wrong"), RconAuth::Failed);
+        assert_eq!(rcon.authenticate(ip, "wrong"), RconAuth::LockedOut);
+        assert!(rcon.is_locked_out(&ip));
+        assert!(!rcon.is_locked_out(&other));
+        // nobody gets in without a password set
+        assert_eq!(shared("", 3).authenticate(ip, ""), RconAuth::Failed);
+    }
+}
@@ -1,9 +1,12 @@
 use crate::imports::*;
 use crate::server::symbols::*;
 use crate::init_flags::*;
-use tokio::future::poll_fn;
-use std::{sync::Arc, net::Shutdown};
+use tokio_util::codec::{Framed, FramedParts};
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use std::{sync::Arc, net::{Ipv4Addr, Ipv6Addr, Shutdown}};
 use crossbeam::sync::ShardedLock;
+use tokio::sync::mpsc::error::TrySendError;
 
 /// Network instance.
 /// TODO make sure clients can't spoof uuid because of the shared uuid socket
@@ -12,58 +15,118 @@ pub struct NetServer {
     pub ani_send: tokio::sync::mpsc::Sender<NetSendMsg>,
     pub ani_recv: crossbeam::Receiver<NetRecvMsg>,
     pub signal_shutdown: tokio::sync::mpsc::UnboundedSender<u64>,
+    /// Connection throttling counters, for monitoring.
+    pub throttle_stats: Arc<JeThrottleStats>,
+    /// Players in play state, kept by the network thread.
+    sessions: Arc<ShardedLock<HashMap<Uuid, JeConnection>>>,
     cc_ptr: &'static ConfigCollection
 }
 
+/// What the server list shows, kept up to date by the network thread.
 pub struct ServerJsonStatus {
     pub server_name: String,
-    pub server_protocol: u16,
     pub online_players: u8,
     pub max_players: u8,
-    pub desc: String,
-    pub favicon: String
+    /// MOTD
+    pub desc: JeChatComponent,
+    /// `data:` URI of `server-icon.png`, empty if there is none.
+    pub favicon: String,
+    /// Everyone in play state, in join order.
+    pub players: Vec<(String, Uuid)>,
+    pub sample_len: usize,
+    pub hide_players: bool
 }
 
 impl ServerJsonStatus {
-    pub fn from(cc: &ConfigCollection) -> ServerJsonStatus {
+    pub fn from(cc: &ConfigCollection, sp: &ServerPrefix) -> ServerJsonStatus {
         Self {
             server_name: cc.net.server_name.to_owned(),
-            server_protocol: 578,
             online_players: 0,
             max_players: cc.auth.max_players,
-            desc: cc.net.server_description.to_owned(),
-            favicon: "".to_owned()
+            desc: JeChatComponent::from_legacy(&cc.net.server_description, &[LEGACY_SECTION, LEGACY_AMPERSAND]),
+            favicon: load_favicon(&sp.path.join("server-icon.png")).unwrap_or_default(),
+            players: Vec::new(),
+            sample_len: cc.net.status_sample_len,
+            hide_players: cc.net.status_hide_players
         }
     }
-    pub fn to_json(&self) -> String {
-        let mut result = serde_json::to_string_pretty(&serde_json::json!({
-            "version": {
-                "name": self.server_name,
-                "protocol": self.server_protocol
-            },
-            "players": {
-                "max": self.max_players,
-                "online": self.online_players,
-                "sample": []
-            },
-            "description": {
-                "extra": [
-                    {
-                        "text": self.desc
-                    }
-                ],
-                "text": ""
-            },
-            //"favicon": "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg=="
-        }))
-        .unwrap();
-        result
+    pub fn add_player(&mut self, username: &str, uuid: &Uuid) {
+        self.players.push((username.to_owned(), uuid.clone()));
+        self.online_players = self.players.len().min(u8::MAX as usize) as u8;
+    }
+    pub fn remove_player(&mut self, uuid: &Uuid) {
+        self.players.retain(|(_, u)| u != uuid);
+        self.online_players = self.players.len().min(u8::MAX as usize) as u8;
+    }
+    /// Clients on a supported version get their own protocol number back so they show as compatible,
+    /// everyone else sees the supported range.
+    pub fn to_json(&self, client_protocol: i32) -> String {
+        let sample_len = self.sample_len.min(self.players.len());
+        let sample: Vec<(&str, Uuid)> = if self.hide_players {
+            // same as vanilla's hide-online-players
+            vec![("Anonymous Player", Uuid::nil()); sample_len]
+        } else {
+            // a different slice every time, so everyone shows up eventually
+            let mut start = [0u8; 4];
+            openssl::rand::rand_bytes(&mut start).unwrap();
+            let start = u32::from_be_bytes(start) as usize;
+            (0..sample_len)
+                .map(|i| &self.players[(start + i) % self.players.len()])
+                .map(|(name, uuid)| (name.as_str(), uuid.clone()))
+                .collect()
+        };
+        let protocol = match JeProtocol::from_number(client_protocol) {
+            Some(_) => client_protocol,
+            None => JeProtocol::newest().number()
+        };
+        server_response_json(
+            &format!("{} {}", &self.server_name, JeProtocol::supported_range()),
+            protocol,
+            self.max_players as u64,
+            self.online_players as u64,
+            &sample,
+            &self.desc,
+            &self.favicon
+        )
     }
 }
 
+/// Read a PNG as a `data:` URI for the status response.
+/// Clients only accept 64x64 icons, anything else is ignored with a warning.
+fn load_favicon(path: &Path) -> Option<String> {
+    let png = match std::fs::read(path) {
+        Ok(png) => png,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Failed to read {:?}: {:?}", path, e);
+            return None;
+        }
+    };
+    // signature, then IHDR with width and height first
+    if png.len() < 24 || &png[..8] != b"\x89PNG\r\n\x1a\n" || &png[12..16] != b"IHDR" {
+        warn!("{:?} is not a PNG, not using it as the server icon", path);
+        return None;
+    }
+    let width = u32::from_be_bytes([png[16], png[17], png[18], png[19]]);
+    let height = u32::from_be_bytes([png[20], png[21], png[22], png[23]]);
+    if (width, height) != (64, 64) {
+        warn!("{:?} is {}x{}, the server icon must be 64x64", path, width, height);
+        return None;
+    }
+    info!("Using {:?} as the server icon", path);
+    Some(format!("data:image/png;base64,{}", openssl::base64::encode_block(&png)))
+}
+
 impl NetServer {
-    pub fn new(vf: ValidatedInitFlags, cc: ConfigCollection, sp: ServerPrefix) -> NetServer {
-        let mut rt = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+    /// Runs on its own thread, with `net_threads` workers for the connections.
+    pub fn new(vf: ValidatedInitFlags, cc: ConfigCollection, sp: ServerPrefix, cli_send: crossbeam::Sender<CliCommand>, net_threads: usize) -> NetServer {
+        let mut rt = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(net_threads.max(1))
+            .thread_name("craftmine-net")
+            .enable_all()
+            .build()
+            .unwrap();
         let (ani_send, mut async_recv) = tokio::sync::mpsc::channel(cc.net.sync_async_channel_len);
         let (shutdown_send, mut shutdown) = tokio::sync::mpsc::unbounded_channel::<u64>();
         let (async_send, ani_recv) = crossbeam::unbounded();
@@ -71,16 +134,109 @@ impl NetServer {
         let cc = Box::leak(Box::new(cc)) as &'static ConfigCollection;
         let sp = Box::leak(Box::new(sp)) as &'static ServerPrefix;
         let rsa_keypair = Box::leak(Box::new(openssl::rsa::Rsa::generate(1024).unwrap())) as &'static openssl::rsa::Rsa<_>;
+        let pubkey_der = Box::leak(Box::new(rsa_keypair.public_key_to_der().unwrap())) as &'static Vec<u8>;
         let server_json_status = Arc::new(ShardedLock::new(
-            ServerJsonStatus::from(&cc)
+            ServerJsonStatus::from(&cc, &sp)
         ));
+        let throttle_stats = Arc::new(JeThrottleStats::default());
+        let net_throttle_stats = Arc::clone(&throttle_stats);
+        let sessions: Arc<ShardedLock<HashMap<Uuid, JeConnection>>> = Arc::new(ShardedLock::new(HashMap::new()));
+        let net_sessions = Arc::clone(&sessions);
         let rt_handle = std::thread::spawn(move || {
             rt.block_on(async {
                 let mut async_recv = async_recv;
+                let sessions = net_sessions;
                 let listen_bind = format!("{}:{}", &vf.bind_addr.0, &vf.je_port.0);
                 let mut listener = tokio::net::TcpListener::bind(&listen_bind).await.unwrap();
+                let session_verifier: Arc<dyn SessionVerifier> = if cc.auth.session_server_mock {
+                    Arc::new(MockSessionVerifier::start().await.unwrap())
+                } else {
+                    Arc::new(HttpSessionVerifier::new(&cc.auth.session_server))
+                };
+                if cc.net.forwarding == ProxyForwarding::Velocity && cc.net.velocity_secret.is_empty() {
+                    warn!("Velocity forwarding is enabled with an empty secret, anyone can forge player details");
+                }
+                if let Some(rcon_port) = cc.net.rcon_port {
+                    if cc.net.rcon_password.is_empty() {
+                        warn!("RCON is enabled without a password, all logins will be refused");
+                    }
+                    match RconServer::bind(&vf.bind_addr.0, &cc, rcon_port, cli_send).await {
+                        Ok(rcon) => {
+                            tokio::task::spawn(rcon.run());
+                        },
+                        Err(e) => {
+                            error!("Failed to bind RCON port {}: {:?}", rcon_port, e);
+                        }
+                    }
+                }
                 let (send_new_conn, mut recv_new_conn) = tokio::sync::mpsc::unbounded_channel::<JeConnection>();
-                let mut map_uuid_conn: HashMap<Uuid, JeConnection> = HashMap::new();
+                let (send_end_conn, mut recv_end_conn) = tokio::sync::mpsc::unbounded_channel::<JeConnection>();
+                let moderation = Arc::new(ShardedLock::new(JeModeration::default()));
+                let shared = Arc::new(JeNetShared {
+                    cc,
+                    sp,
+                    rsa_keypair,
+                    pubkey_der,
+                    server_json_status: Arc::clone(&server_json_status),
+                    session_verifier,
+                    send_new_conn,
+                    send_end_conn,
+                    send_game: async_send.clone(),
+                    moderation: Arc::clone(&moderation),
+                    throttle: JeThrottle::new(&cc.net, net_throttle_stats),
+                    registries: JeRegistries::new()
+                });
+                {
+                    let shared = Arc::clone(&shared);
+                    tokio::task::spawn(async move {
+                        let mut prune = tokio::time::interval(Duration::from_secs(60));
+                        loop {
+                            prune.tick().await;
+                            shared.throttle.prune();
+                        }
+                    });
+                }
+                if let Some(query_port) = cc.net.query_port {
+                    match QueryServer::bind(
+                        &vf.bind_addr.0,
+                        query_port,
+                        vf.je_port.0,
+                        &cc.auth.default_world_name,
+                        Arc::clone(&server_json_status),
+                        Arc::clone(&sessions)
+                    ).await {
+                        Ok(query) => {
+                            tokio::task::spawn(query.run(Duration::from_secs(cc.net.query_token_secs)));
+                        },
+                        Err(e) => {
+                            error!("Failed to bind query port {}: {:?}", query_port, e);
+                        }
+                    }
+                }
+                match BeServer::bind(
+                    &vf.bind_addr.0,
+                    vf.be_port.0,
+                    &cc.auth.default_world_name,
+                    Arc::clone(&server_json_status),
+                    Box::new(BeNoSessions)
+                ).await {
+                    Ok(be) => {
+                        tokio::task::spawn(be.run());
+                    },
+                    Err(e) => {
+                        error!("Failed to bind BE port {}: {:?}", vf.be_port.0, e);
+                    }
+                }
+                // replays connect over loopback when listening on every address
+                let replay_addr = match listener.local_addr() {
+                    Ok(addr) if addr.ip().is_unspecified() => SocketAddr::new(
+                        if addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() },
+                        addr.port()
+                    ),
+                    Ok(addr) => addr,
+                    Err(_) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), vf.je_port.0)
+                };
+                let mut joins = JeJoins::default();
                 let mut async_net_active = true;
                 info!("Listening on {}", &listen_bind);
                 //let mut streams = HashMap::new();
@@ -88,301 +244,278 @@ impl NetServer {
                     tokio::select! {
                         Some(net_msg) = async_recv.recv() => {
                             debug!("SENDOUT {:?}", &net_msg);
-                            match net_msg {
-                                NetSendMsg::Disconnect(uuid, reason) => {
-                                    if let Some(conn) = map_uuid_conn.get(&uuid) {
-                                        conn.send(JePlayDisconnect {
-                                            reason: JeChat(reason)
-                                        });
-                                        map_uuid_conn.remove(&uuid);
-                                    } else {
-                                        error!("Trying to disconnect nonexistent uuid");
-                                    }
-                                },
-                                NetSendMsg::Single(uuid, packet_id, data) => {
-                                    if let Some(conn) = map_uuid_conn.get(&uuid) {
-                                        conn.send_raw(packet_id, &data);
-                                    } else {
-                                        error!("Trying to send single to nonexistent uuid");
-                                    }
-                                }
-                                _ => {}
-                            }
+                            route(net_msg, &mut sessions.write().unwrap(), &moderation, replay_addr);
                         },
                         Some(_) = shutdown.recv() => {
                             async_net_active = false;
                         }
                         Some(new_conn) = recv_new_conn.recv() => {
-                            let (uuid, conn) = (new_conn.uuid.clone(), new_conn);
-                            debug!("Adding session {:?}, {:?}", &uuid, &conn);
-                            info!("{} ({}) has joined the server from {}", &conn.username, &conn.uuid, &conn.addr);
-                            
-                            async_send.send(NetRecvMsg {
-                                uuid: uuid.clone(),
-                                inner: NetRecvInner::NewSession {
-                                    username: conn.username.clone(),
-                                    online: conn.online
+                            let uuid = new_conn.uuid;
+                            match joins.on_login(new_conn) {
+                                JeJoin::Now(conn) => {
+                                    join(conn, &sessions, &server_json_status, &async_send);
+                                },
+                                JeJoin::Wait(superseded) => {
+                                    info!("{} logged in again, closing the previous connection", &uuid);
+                                    disconnect(&mut sessions.write().unwrap(), &uuid, JE_LOGGED_IN_ELSEWHERE.to_owned());
+                                    if let Some(superseded) = superseded {
+                                        superseded.send(JePlayDisconnect {
+                                            reason: JeChat::legacy(JE_LOGGED_IN_ELSEWHERE)
+                                        });
+                                    }
                                 }
-                            });
-                            map_uuid_conn.insert(uuid, conn);
+                            }
+                        },
+                        Some(conn) = recv_end_conn.recv() => {
+                            let (ended, next) = joins.on_close(&conn);
+                            if ended {
+                                let uuid = conn.uuid;
+                                {
+                                    let mut sessions = sessions.write().unwrap();
+                                    // unless already dropped by a kick
+                                    if matches!(sessions.get(&uuid), Some(current) if Arc::ptr_eq(&current.outbound, &conn.outbound)) {
+                                        sessions.remove(&uuid);
+                                    }
+                                }
+                                server_json_status.write().unwrap().remove_player(&uuid);
+                                if async_send.send(NetRecvMsg {
+                                    uuid,
+                                    inner: NetRecvInner::EndSession
+                                }).is_err() {
+                                    error!("Game thread gone, {} won't be despawned", &uuid);
+                                }
+                            }
+                            if let Some(next) = next {
+                                join(next, &sessions, &server_json_status, &async_send);
+                            }
                         },
                         Ok((stream, addr)) = listener.accept() => {
-                            let send_new_conn = send_new_conn.clone();
-                            let async_send = async_send.clone();
                             //streams.insert(addr, stream);
-                            let server_json_status = Arc::clone(&server_json_status);
+                            let shared = Arc::clone(&shared);
                             tokio::task::spawn(async move {
-                                // TODO timeout
-                                let mut je_client = stream;
-                                let mut state = 0;
-                                let mut last_seen = tokio::time::Instant::now();
+                                let mut stream = stream;
+                                let login_timeout = Duration::from_secs(shared.cc.net.login_timeout_secs);
+                                let mut addr = addr;
+                                let proxy_protocol = shared.cc.net.proxy_protocol;
+                                let allow_direct = shared.cc.net.proxy_protocol_allow_direct;
+                                let trusted = proxy_protocol && is_trusted_proxy(&addr.ip(), &shared.cc.net.proxy_protocol_trusted);
+                                if proxy_protocol && !trusted && !allow_direct {
+                                    debug!("{} isn't a trusted proxy, closing", &addr);
+                                    let _ = stream.shutdown(Shutdown::Both);
+                                    return;
+                                }
+                                // a forwarding proxy's address is shared by all its players, as is a balancer's
+                                let per_ip = shared.cc.net.forwarding == ProxyForwarding::None;
+                                let pending_slot = match shared.throttle.on_connect(addr.ip(), per_ip && !trusted) {
+                                    Ok(slot) => slot,
+                                    Err(refusal) => {
+                                        debug!("{} refused: {:?}, closing", &addr, refusal);
+                                        let _ = stream.shutdown(Shutdown::Both);
+                                        return;
+                                    }
+                                };
+                                // read past a PROXY header, for the legacy ping check and the codec
+                                let mut received = BytesMut::new();
+                                if proxy_protocol {
+                                    let header = match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream)).await {
+                                        Ok(Ok((header, rest))) => {
+                                            received = rest;
+                                            header
+                                        },
+                                        Ok(Err(e)) => {
+                                            debug!("{} bad PROXY header {:?}, closing", &addr, e);
+                                            let _ = stream.shutdown(Shutdown::Both);
+                                            return;
+                                        },
+                                        Err(_) => {
+                                            debug!("{} sent nothing, closing", &addr);
+                                            return;
+                                        }
+                                    };
+                                    match header {
+                                        ProxyHeader::Absent if !allow_direct => {
+                                            debug!("{} sent no PROXY header, closing", &addr);
+                                            let _ = stream.shutdown(Shutdown::Both);
+                                            return;
+                                        },
+                                        ProxyHeader::Absent => {},
+                                        _ if !trusted => {
+                                            warn!("{} sent a PROXY header but isn't trusted, closing", &addr);
+                                            let _ = stream.shutdown(Shutdown::Both);
+                                            return;
+                                        },
+                                        ProxyHeader::Unknown => {},
+                                        ProxyHeader::Source(source) => {
+                                            debug!("{} is a proxy for {}", &addr, &source);
+                                            if per_ip {
+                                                if let Err(refusal) = shared.throttle.on_proxied_connect(source.ip()) {
+                                                    debug!("{} refused: {:?}, closing", &source, refusal);
+                                                    let _ = stream.shutdown(Shutdown::Both);
+                                                    return;
+                                                }
+                                            }
+                                            addr = source;
+                                        }
+                                    }
+                                }
                                 info!("New JE client from {}", &addr);
-                                let mut peek_buf = [0u8; 1];
-                                //tokio::pin!(je_client);
-                                let (send_to_session, mut recv_send_to_session) = tokio::sync::mpsc::unbounded_channel::<(i32, Vec<u8>)>();
-                                let mut conn: Option<JeConnection> = None;
-                                let mut run = true;
-                                'streamloop: while run {
-                                    let send_to_session = send_to_session.clone();
-
-                                    let poll_try = poll_fn(|mut cx| je_client.poll_peek(&mut cx, &mut peek_buf));
+                                match tokio::time::timeout(login_timeout, try_legacy_ping(&mut stream, &received, &shared.server_json_status)).await {
+                                    Ok(Ok(false)) => {},
+                                    Ok(Ok(true)) => {
+                                        debug!("{} answered legacy ping", &addr);
+                                        let _ = stream.shutdown(Shutdown::Both);
+                                        return;
+                                    },
+                                    Ok(Err(e)) => {
+                                        debug!("{} failed before first packet: {:?}", &addr, e);
+                                        return;
+                                    },
+                                    Err(_) => {
+                                        debug!("{} sent nothing, closing", &addr);
+                                        return;
+                                    }
+                                }
+                                let (outbound, mut recv_outbound) = JeOutboundQueue::new(&shared.cc.net);
+                                let mut parts = FramedParts::new::<JeFrame>(stream, JeCodec::new(shared.cc.net.max_packet_len));
+                                parts.read_buf = received;
+                                let mut session = JeSession::new(
+                                    addr,
+                                    Framed::from_parts(parts),
+                                    outbound,
+                                    pending_slot,
+                                    Arc::clone(&shared)
+                                );
+                                if shared.cc.net.capture_packets {
+                                    match JeCaptureWriter::create(&shared.sp.path.join(JE_CAPTURES_DIR), &addr) {
+                                        Ok(capture) => {
+                                            debug!("{} capturing to {:?}", &addr, capture.path());
+                                            session.framed.codec_mut().set_capture(capture);
+                                        },
+                                        Err(e) => {
+                                            warn!("{} failed to start capture: {:?}", &addr, e);
+                                        }
+                                    }
+                                }
+                                // login deadline, idle timeout and keep-alives
+                                let mut timers = tokio::time::interval(Duration::from_secs(1));
+                                while session.run {
                                     tokio::select! {
-                                        Some(msg_to_session) = recv_send_to_session.recv() => {
+                                        Some(msg_to_session) = recv_outbound.recv() => {
                                             debug!("{} <- new msg", &addr);
-                                            match &conn {
-                                                Some(c) => {
-                                                    match &c.enc {
-                                                        Some(s_enc) => {
-                                                            unimplemented!()
-                                                        },
-                                                        None => {
-                                                            write_to_je_raw(&mut je_client, msg_to_session.0, &msg_to_session.1).await;
+                                            let len = msg_to_session.1.len();
+                                            match &session.conn {
+                                                Some(_) => {
+                                                    let closing = msg_to_session.0 == JePlayDisconnect::default().get_packet_id().0;
+                                                    // nothing but the Disconnect once the client has fallen behind
+                                                    if (closing || !session.outbound.is_too_slow())
+                                                        && session.framed.send(JeFrame::from(msg_to_session)).await.is_err() {
+                                                        debug!("{} write failed, closing", &addr);
+                                                        session.run = false;
+                                                    }
+                                                    if closing {
+                                                        if session.outbound.is_too_slow() {
+                                                            info!("{} can't keep up with its outbound queue, closing", &addr);
                                                         }
+                                                        session.run = false;
                                                     }
                                                 },
                                                 None => {
                                                     warn!("{} unexpected outbound packet to incomplete connection", &addr);
                                                 }
                                             }
+                                            session.outbound.written(len);
                                         }
-                                        poll_result = poll_try => {
-                                            match poll_result {
-                                                Ok(0) => {
-                                                    debug!("{} poll_peek len 0, closing", &addr);
-                                                    run = false;
-                                                }
-                                                Ok(bytes_avail) => {
-                                                    match read_from_je(&mut je_client).await {
-                                                        Ok((packet_len, packet_id, packet_data)) => {
-                                                            debug!("{} IN P (len {} id {}) DATA\n\t{:?}", &addr, &packet_len, &packet_id, &packet_data);
-                                                            match state {
-                                                                0 => {
-                                                                    debug!("{} state 0, parsing as handshake scanning for next", &addr);
-                                                                    if let Ok(pk_handshake) = JePacketHandshake::try_from_raw(&packet_data) {
-                                                                        state = pk_handshake.next_state.0;
-                                                                        debug!("{} state -> {}", &addr, state);
-                                                                    } else {
-                                                                        debug!("DE: decode JePacketHandshake failed, skipping");
-                                                                    }
-                                                                }
-                                                                1 => {
-                                                                    match packet_id {
-                                                                        0 => {
-                                                                            // reply
-                                                                            debug!("@{} <<< query meta", &addr);
-                                                                            // lock status
-                                                                            let json = {
-                                                                                let status_lock = server_json_status.read().unwrap();
-                                                                                status_lock.to_json()
-                                                                            };
-                                                                            JeHandshakeResponse {
-                                                                                json: json
-                                                                            }.write_to_stream(&mut je_client).await;
-                                                                        },
-                                                                        1 => {
-                                                                            // pong
-                                                                            if let Ok(ping) = JePacketPing::try_from_raw(&packet_data) {
-                                                                                JePacketPong {
-                                                                                    val: ping.val
-                                                                                }.write_to_stream(&mut je_client).await;
-                                                                            } else {
-                                                                                debug!("invalid ping packet");
-                                                                            }
-                                                                        },
-                                                                        _ => {}
-                                                                    }
-                                                                }
-                                                                2 => {
-                                                                    if let Ok(pk_login_start) = JeLoginStart::try_from_raw(&packet_data) {
-                                                                        debug!("try_pk_login_raw ok");
-                                                                        if cc.auth.online_mode {
-                                                                            let pubkey = rsa_keypair.public_key_to_der().unwrap();
-                                                                            // TODO randomly generate vtoken instead
-                                                                            let vtoken = vec![0u8, 1u8, 2u8, 3u8];
-                                                                            // send enc request
-                                                                            debug!("Sending enc request");
-                                                                            JeEncRequest {
-                                                                                server_id: "".to_owned(),
-                                                                                pubkey_len: JeVarInt(pubkey.len() as i32),
-                                                                                pubkey: pubkey,
-                                                                                vtoken_len: JeVarInt(vtoken.len() as i32),
-                                                                                vtoken: vtoken.clone()
-                                                                            }.write_to_stream(&mut je_client).await;
-                                                                        } else {
-                                                                            if let Ok(offline_user) = sp.users.load_or_new_offline(&pk_login_start.name, &cc) {
-                                                                                JeLoginSuccess {
-                                                                                    uuid: offline_user.uuid.clone().to_hyphenated().to_string(),
-                                                                                    username: offline_user.username.clone()
-                                                                                }.write_to_stream(&mut je_client).await;
-                                                                                state = 3;
-                                                                                
-                                                                                let new_conn = JeConnection {
-                                                                                    state: state,
-                                                                                    enc: None,
-                                                                                    uuid: offline_user.uuid.clone(),
-                                                                                    addr: addr.clone(),
-                                                                                    username: pk_login_start.name.clone(),
-                                                                                    send: send_to_session,
-                                                                                    online: false
-                                                                                };
-                                                                                send_new_conn.send(new_conn.clone());
-                                                                                conn = Some(new_conn);
-                                                                            } else {
-                                                                                // failed to get offline user info, shutdown
-                                                                                error!("Failed to load or create offline user record, terminating connection");
-                                                                                run = false;
-                                                                            }
-
-                                                                            // join game
-                                                                            /*write_to_je(&mut je_client, 0x26, &[
-                                                                                JeNetVal::Int(0x01000000),  // eid
-                                                                                JeNetVal::UByte(0x1),       // gamemode
-                                                                                JeNetVal::Int(0),           // dimension
-                                                                                JeNetVal::Long(0x0),        // hashed seed
-                                                                                JeNetVal::UByte(20),        // max players
-                                                                                JeNetVal::String("flat".to_owned()),    // level type
-                                                                                JeNetVal::VarInt(8),        // view distance
-                                                                                JeNetVal::Boolean(false),   // reduced debug
-                                                                                JeNetVal::Boolean(false)    // enable respawn
-
-                                                                            ]).await;
-                                                                            // initial play state
-                                                                            /*debug!("sending initial play state");
-                                                                            write_to_je(&mut je_client, 0x00, &[
-                                                                                JeNetVal::VarInt(0),
-                                                                                JeNetVal::Array(Uuid::parse_str("550e8400-e29b-41d4-a716-446655440000").unwrap().as_bytes().to_vec()),
-                                                                                JeNetVal::VarInt(106),
-                                                                                JeNetVal::Double(0.0f64),
-                                                                                JeNetVal::Double(0.0f64),
-                                                                                JeNetVal::Double(0.0f64),
-                                                                                JeNetVal::UByte(0),
-                                                                                JeNetVal::UByte(0),
-                                                                                JeNetVal::Int(0), // data
-                                                                                JeNetVal::Short(0),
-                                                                                JeNetVal::Short(0),
-                                                                                JeNetVal::Short(0)
-                                                                            ]).await;*/
-                                                                            // ignore client stuff for now
-                                                                            // held item change
-                                                                            write_to_je(&mut je_client, 0x40, &[
-                                                                                JeNetVal::Byte(0)
-                                                                            ]).await;
-                                                                            // spawn position
-                                                                            write_to_je(&mut je_client, 0x4e, &[
-                                                                                JeNetVal::Long(0)
-                                                                            ]).await;
-                                                                            // player position and look
-                                                                            write_to_je(&mut je_client, 0x36, &[
-                                                                                JeNetVal::Double(0f64),
-                                                                                JeNetVal::Double(0f64),
-                                                                                JeNetVal::Double(0f64),
-                                                                                JeNetVal::Float(0.0f32),
-                                                                                JeNetVal::Float(0.0f32),
-                                                                                JeNetVal::UByte(0),
-                                                                                JeNetVal::VarInt(1)
-                                                                            ]).await;*/
-                                                                        }
-                                                                    } else {
-                                                                        debug!("DE login start err");
-                                                                    }
-                                                                }
-                                                                3 => {
-                                                                    debug!("play state");
-                                                                }
-                                                                _ => {
-                                                                    debug!("unknown state");
-                                                                }
-                                                            }
+                                        _ = timers.tick() => {
+                                            session.check_timers().await;
+                                        }
+                                        frame = session.framed.next() => {
+                                            match frame {
+                                                Some(Ok(frame)) => {
+                                                    session.last_seen = Instant::now();
+                                                    debug!("{} IN P (len {} id {}) DATA\n\t{:?}", &addr, frame.data.len(), &frame.id, &frame.data);
+                                                    // the handler borrows the session until the match ends
+                                                    let invalid = match shared.registries.get(session.state).dispatch(&mut session, frame.id, &frame.data) {
+                                                        Ok(handler) => {
+                                                            handler.await;
+                                                            None
                                                         },
-                                                        Err(_) => {
-                                                            debug!("DE: @{} malformed packet, skipping", &addr);
-
-                                                        }
+                                                        Err(JeDispatchError::UnknownId(state, id)) => {
+                                                            warn!("{} unexpected packet id {:#04x} in {:?} state", &addr, id, state);
+                                                            None
+                                                        },
+                                                        Err(JeDispatchError::Malformed(state, id, e)) => Some((state, id, e))
+                                                    };
+                                                    if let Some((state, id, e)) = invalid {
+                                                        session.on_invalid_packet(state, id, e).await;
                                                     }
                                                 },
-                                                Err(e) => {
-                                                    debug!("DE: poll_peek failed {:?} closing", e);
-                                                    run = false;
+                                                Some(Err(e)) => {
+                                                    debug!("DE: @{} bad frame {:?}, closing", &addr, e);
+                                                    session.run = false;
+                                                },
+                                                None => {
+                                                    debug!("{} stream closed", &addr);
+                                                    session.run = false;
                                                 }
                                             }
                                         }
                                     };
                                 }
-                                if let Some(c) = conn {
-                                    &async_send.send(NetRecvMsg {
-                                        uuid: c.uuid,
-                                        inner: NetRecvInner::EndSession
-                                    });
+                                if let Some(c) = &session.conn {
+                                    let _ = shared.send_end_conn.send(c.clone());
                                 }
-                                je_client.shutdown(Shutdown::Both);
+                                let _ = session.framed.get_ref().shutdown(Shutdown::Both);
 
                             });
                         }
                     }
                 }
                 info!("Async net thread shutting down");
-                
-                unsafe {
-                    drop(Box::from_raw(
-                        std::mem::transmute::<_, *mut ConfigCollection>(cc)
-                    ));
-                    drop(Box::from_raw(
-                        std::mem::transmute::<_, *mut ServerPrefix>(sp)
-                    ));
-                }
             });
+            // workers may still be running connection tasks that borrow the configs
+            drop(rt);
+            unsafe {
+                drop(Box::from_raw(
+                    std::mem::transmute::<_, *mut ConfigCollection>(cc)
+                ));
+                drop(Box::from_raw(
+                    std::mem::transmute::<_, *mut ServerPrefix>(sp)
+                ));
+            }
         });
         Self {
             rt_handle,
             ani_send,
             ani_recv,
             cc_ptr: cc,
-            signal_shutdown: shutdown_send
+            signal_shutdown: shutdown_send,
+            throttle_stats,
+            sessions
         }
     }
 
     pub fn all(&mut self, packet_id: i32, data: &[u8]) {
-        self.ani_send.send(NetSendMsg::All(
+        self.send_msg(NetSendMsg::All(
             packet_id, data.to_owned()
         ));
     }
     pub fn broadcast<T: JePacket>(&mut self, to: &[Uuid], packet: T) {
-        self.ani_send.send(NetSendMsg::Broadcast(
+        self.send_msg(NetSendMsg::Broadcast(
             to.to_owned(), packet.get_packet_id().0, packet.to_vec_u8()
         ));
     }
     pub fn single<T: JePacket>(&mut self, to: &Uuid, packet: T) {
-        self.ani_send.send(NetSendMsg::Single(
+        self.send_msg(NetSendMsg::Single(
             to.to_owned(), packet.get_packet_id().0, packet.to_vec_u8()
         ));
     }
     pub fn disconnect(&mut self, to: &Uuid, msg: &str) {
-        self.ani_send.send(NetSendMsg::Disconnect(
+        self.send_msg(NetSendMsg::Disconnect(
             to.to_owned(), msg.to_owned()
         ));
     }
     pub fn timeout(&mut self, player: &Uuid, duration: Option<Duration>, msg: &str) {
-        self.ani_send.send(
+        self.send_msg(
             if let Some(dur) = duration {
                 NetSendMsg::DefiniteTimeout(player.to_owned(), dur, msg.to_owned())
             } else {
@@ -390,4 +523,225 @@ impl NetServer {
             }
         );
     }
-}
\ No newline at end of file
+    pub fn unset_timeout(&mut self, player: &Uuid) {
+        self.send_msg(NetSendMsg::UnsetTimeout(player.to_owned()));
+    }
+    pub fn block(&mut self, player: &Uuid, until: Instant, msg: &str) {
+        self.send_msg(NetSendMsg::SetBlock(
+            player.to_owned(), until, msg.to_owned()
+        ));
+    }
+    pub fn unblock(&mut self, player: &Uuid) {
+        self.send_msg(NetSendMsg::UnsetBlock(player.to_owned()));
+    }
+    /// Each player's outbound queue, by name.
+    pub fn outbound_queues(&self) -> Vec<(String, Arc<JeOutboundQueue>)> {
+        let mut queues: Vec<(String, Arc<JeOutboundQueue>)> = self.sessions.read().unwrap().values()
+            .map(|conn| (conn.username.clone(), Arc::clone(&conn.outbound)))
+            .collect();
+        queues.sort_by(|a, b| a.0.cmp(&b.0));
+        queues
+    }
+    /// Results are logged by the network thread.
+    pub fn replay(&mut self, capture: &Path) {
+        self.send_msg(NetSendMsg::Replay(capture.to_owned()));
+    }
+    /// Nothing is dropped: the game thread isn't async, so it blocks while the channel is full.
+    fn send_msg(&mut self, msg: NetSendMsg) {
+        let msg = match self.ani_send.try_send(msg) {
+            Ok(()) => return,
+            Err(TrySendError::Full(msg)) => {
+                debug!("Channel to network thread full, waiting");
+                msg
+            },
+            Err(TrySendError::Closed(msg)) => {
+                error!("Network thread gone, dropping {:?}", msg);
+                return;
+            }
+        };
+        if let Err(e) = futures::executor::block_on(self.ani_send.send(msg)) {
+            error!("Network thread gone, dropping {:?}", e.0);
+        }
+    }
+}
+
+/// Send a Disconnect and forget the connection, the session task closes the socket after sending it.
+/// Returns `false` if `uuid` isn't connected.
+/// Hand a connection that reached play state to the game thread.
+fn join(
+    conn: JeConnection,
+    sessions: &ShardedLock<HashMap<Uuid, JeConnection>>,
+    server_json_status: &ShardedLock<ServerJsonStatus>,
+    async_send: &crossbeam::Sender<NetRecvMsg>
+) {
+    let uuid = conn.uuid;
+    debug!("Adding session {:?}, {:?}", &uuid, &conn);
+    info!("{} ({}) has joined the server from {}", &conn.username, &conn.uuid, &conn.addr);
+    if async_send.send(NetRecvMsg {
+        uuid,
+        inner: NetRecvInner::NewSession {
+            username: conn.username.clone(),
+            online: conn.online,
+            properties: conn.properties.clone()
+        }
+    }).is_err() {
+        error!("Game thread gone, {} won't be spawned", &conn.username);
+    }
+    server_json_status.write().unwrap().add_player(&conn.username, &uuid);
+    sessions.write().unwrap().insert(uuid, conn);
+}
+
+/// Act on a message from the game thread.
+fn route(net_msg: NetSendMsg, map_uuid_conn: &mut HashMap<Uuid, JeConnection>, moderation: &ShardedLock<JeModeration>, replay_addr: SocketAddr) {
+    match net_msg {
+        NetSendMsg::All(packet_id, data) => {
+            for conn in map_uuid_conn.values() {
+                conn.send_raw(packet_id, &data);
+            }
+        },
+        NetSendMsg::Broadcast(uuids, packet_id, data) => {
+            for uuid in &uuids {
+                if let Some(conn) = map_uuid_conn.get(uuid) {
+                    conn.send_raw(packet_id, &data);
+                } else {
+                    debug!("Skipping broadcast to nonexistent uuid {}", uuid);
+                }
+            }
+        },
+        NetSendMsg::Disconnect(uuid, reason) => {
+            if !disconnect(map_uuid_conn, &uuid, reason) {
+                error!("Trying to disconnect nonexistent uuid");
+            }
+        },
+        NetSendMsg::Single(uuid, packet_id, data) => {
+            if let Some(conn) = map_uuid_conn.get(&uuid) {
+                conn.send_raw(packet_id, &data);
+            } else {
+                error!("Trying to send single to nonexistent uuid");
+            }
+        },
+        NetSendMsg::DefiniteTimeout(uuid, duration, reason) => {
+            moderation.write().unwrap().set_timeout(uuid, Some(Instant::now() + duration), reason.clone());
+            disconnect(map_uuid_conn, &uuid, reason);
+        },
+        NetSendMsg::IndefiniteTimeout(uuid, reason) => {
+            moderation.write().unwrap().set_timeout(uuid, None, reason.clone());
+            disconnect(map_uuid_conn, &uuid, reason);
+        },
+        NetSendMsg::UnsetTimeout(uuid) => {
+            moderation.write().unwrap().unset_timeout(&uuid);
+        },
+        NetSendMsg::SetBlock(uuid, until, reason) => {
+            {
+                let mut moderation = moderation.write().unwrap();
+                moderation.prune();
+                moderation.set_block(uuid, until, reason.clone());
+            }
+            disconnect(map_uuid_conn, &uuid, reason);
+        },
+        NetSendMsg::UnsetBlock(uuid) => {
+            moderation.write().unwrap().unset_block(&uuid);
+        },
+        NetSendMsg::Replay(path) => {
+            tokio::task::spawn(replay_and_log(replay_addr, path));
+        }
+    }
+}
+
+fn disconnect(map_uuid_conn: &mut HashMap<Uuid, JeConnection>, uuid: &Uuid, reason: String) -> bool {
+    match map_uuid_conn.remove(uuid) {
+        Some(conn) => {
+            conn.send(JePlayDisconnect {
+                reason: JeChat::legacy(&reason)
+            });
+            true
+        },
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    type JeOutboundRecv = UnboundedReceiver<(i32, Vec<u8>)>;
+
+    fn sessions(players: &[Uuid]) -> (HashMap<Uuid, JeConnection>, Vec<JeOutboundRecv>) {
+        let mut sessions = HashMap::new();
+        let mut outbound = Vec::new();
+        for &uuid in players {
+            let (queue, recv) = JeOutboundQueue::new(&ConfigNet::default());
+            sessions.insert(uuid, JeConnection {
+                state: ConnectionState::Play,
+                enc: None,
+                uuid,
+                username: uuid.to_string(),
+                addr: "127.0.0.1:50000".parse().unwrap(),
+                outbound: queue,
+                online: false,
+                properties: Vec::new(),
+                latency: Arc::new(AtomicU32::new(0))
+            });
+            outbound.push(recv);
+        }
+        (sessions, outbound)
+    }
+
+    fn replay_addr() -> SocketAddr {
+        "127.0.0.1:25565".parse().unwrap()
+    }
+
+    #[test]
+    fn single_goes_to_one_player() {
+        let players = [Uuid::from_u128(1), Uuid::from_u128(2)];
+        let (mut sessions, mut outbound) = sessions(&players);
+        let moderation = ShardedLock::new(JeModeration::default());
+        route(NetSendMsg::Single(players[1], 0x0e, vec![1, 2]), &mut sessions, &moderation, replay_addr());
+        assert!(outbound[0].try_recv().is_err());
+        assert_eq!(outbound[1].try_recv().unwrap(), (0x0e, vec![1, 2]));
+        // nobody to send to, nothing happens
+        route(NetSendMsg::Single(Uuid::from_u128(3), 0x0e, vec![1, 2]), &mut sessions, &moderation, replay_addr());
+        assert!(outbound.iter_mut().all(|recv| recv.try_recv().is_err()));
+    }
+
+    #[test]
+    fn broadcast_and_all() {
+        let players = [Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3)];
+        let (mut sessions, mut outbound) = sessions(&players);
+        let moderation = ShardedLock::new(JeModeration::default());
+        route(NetSendMsg::Broadcast(vec![players[0], players[2], Uuid::from_u128(4)], 0x0e, vec![1]), &mut sessions, &moderation, replay_addr());
+        assert_eq!(outbound[0].try_recv().unwrap(), (0x0e, vec![1]));
+        assert!(outbound[1].try_recv().is_err());
+        assert_eq!(outbound[2].try_recv().unwrap(), (0x0e, vec![1]));
+
+        route(NetSendMsg::All(0x0e, vec![2]), &mut sessions, &moderation, replay_addr());
+        for recv in &mut outbound {
+            assert_eq!(recv.try_recv().unwrap(), (0x0e, vec![2]));
+        }
+    }
+
+    #[test]
+    fn timeout_and_block_disconnect() {
+        let players = [Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3)];
+        let (mut sessions, mut outbound) = sessions(&players);
+        let moderation = ShardedLock::new(JeModeration::default());
+        let disconnect_id = JePlayDisconnect::default().get_packet_id().0;
+        route(NetSendMsg::DefiniteTimeout(players[0], Duration::from_secs(60), "Timed out".to_owned()), &mut sessions, &moderation, replay_addr());
+        route(NetSendMsg::IndefiniteTimeout(players[1], "Timed out".to_owned()), &mut sessions, &moderation, replay_addr());
+        route(NetSendMsg::SetBlock(players[2], Instant::now() + Duration::from_secs(60), "Blocked".to_owned()), &mut sessions, &moderation, replay_addr());
+        assert!(sessions.is_empty());
+        for (uuid, recv) in players.iter().zip(&mut outbound) {
+            assert_eq!(recv.try_recv().unwrap().0, disconnect_id);
+            assert!(moderation.read().unwrap().refusal(uuid).is_some());
+        }
+
+        route(NetSendMsg::UnsetTimeout(players[1]), &mut sessions, &moderation, replay_addr());
+        route(NetSendMsg::UnsetBlock(players[2]), &mut sessions, &moderation, replay_addr());
+        let moderation = moderation.read().unwrap();
+        assert_eq!(moderation.refusal(&players[0]).as_deref(), Some("Timed out"));
+        assert_eq!(moderation.refusal(&players[1]), None);
+        assert_eq!(moderation.refusal(&players[2]), None);
+    }
+}
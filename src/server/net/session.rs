@@ -0,0 +1,204 @@
+use crate::imports::*;
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Game profile returned by a session server's `hasJoined`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JeGameProfile {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub properties: Vec<JeProfileProperty>
+}
+
+/// Profile property, usually `textures` (skin and cape).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JeProfileProperty {
+    pub name: String,
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>
+}
+
+#[derive(Debug)]
+pub enum SessionVerifyError {
+    Http(reqwest::Error),
+    UnexpectedStatus(u16)
+}
+
+/// Checks that a client has announced itself to the session server.
+#[async_trait]
+pub trait SessionVerifier: Send + Sync {
+    /// `Ok(None)` means the session server doesn't know about the client.
+    async fn has_joined(&self, username: &str, server_hash: &str) -> Result<Option<JeGameProfile>, SessionVerifyError>;
+}
+
+/// The server hash sent to `hasJoined`.
+/// Java prints SHA-1 digests as a signed two's complement `BigInteger` in hex, so mimic that.
+pub fn server_hash(server_id: &str, shared_secret: &[u8], pubkey_der: &[u8]) -> String {
+    let mut hasher = openssl::sha::Sha1::new();
+    hasher.update(server_id.as_bytes());
+    hasher.update(shared_secret);
+    hasher.update(pubkey_der);
+    let mut digest = hasher.finish();
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        // negate: invert then add one
+        let mut carry = true;
+        for b in digest.iter_mut().rev() {
+            *b = !*b;
+            if carry {
+                let (sum, overflow) = b.overflowing_add(1);
+                *b = sum;
+                carry = overflow;
+            }
+        }
+    }
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    let hex = hex.trim_start_matches('0');
+    format!("{}{}", if negative { "-" } else { "" }, hex)
+}
+
+/// Queries a Mojang-compatible session server over HTTP.
+pub struct HttpSessionVerifier {
+    base_url: String,
+    client: reqwest::Client
+}
+
+impl HttpSessionVerifier {
+    pub fn new(base_url: &str) -> HttpSessionVerifier {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            client: reqwest::Client::new()
+        }
+    }
+}
+
+#[async_trait]
+impl SessionVerifier for HttpSessionVerifier {
+    async fn has_joined(&self, username: &str, server_hash: &str) -> Result<Option<JeGameProfile>, SessionVerifyError> {
+        let resp = self.client
+            .get(&format!("{}/session/minecraft/hasJoined", &self.base_url))
+            .query(&[("username", username), ("serverId", server_hash)])
+            .send().await
+            .map_err(SessionVerifyError::Http)?;
+        match resp.status().as_u16() {
+            200 => resp.json::<JeGameProfile>().await
+                .map(Some)
+                .map_err(SessionVerifyError::Http),
+            204 => Ok(None),
+            other => Err(SessionVerifyError::UnexpectedStatus(other))
+        }
+    }
+}
+
+/// A stand-in session server on loopback, so online mode works without network access.
+/// Every client is accepted with a profile derived from its username.
+pub struct MockSessionVerifier {
+    pub addr: SocketAddr,
+    http: HttpSessionVerifier
+}
+
+impl MockSessionVerifier {
+    /// Must be called from within the network runtime.
+    pub async fn start() -> std::io::Result<MockSessionVerifier> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::task::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::task::spawn(mock_respond(stream));
+                    },
+                    Err(e) => {
+                        warn!("Mock session server stopped accepting: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        warn!("Using mock session server at {}, any username will be accepted", &addr);
+        Ok(Self {
+            addr,
+            http: HttpSessionVerifier::new(&format!("http://{}", &addr))
+        })
+    }
+    /// The profile the mock hands out for `username`.
+    pub fn profile_for(username: &str) -> JeGameProfile {
+        JeGameProfile {
+            id: Uuid::new_v5(&Uuid::NAMESPACE_OID, format!("MockPlayer:{}", username).as_bytes()),
+            name: username.to_owned(),
+            properties: vec![]
+        }
+    }
+}
+
+#[async_trait]
+impl SessionVerifier for MockSessionVerifier {
+    async fn has_joined(&self, username: &str, server_hash: &str) -> Result<Option<JeGameProfile>, SessionVerifyError> {
+        self.http.has_joined(username, server_hash).await
+    }
+}
+
+/// Answer a single `hasJoined` request.
+async fn mock_respond(mut stream: TcpStream) {
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8192 {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => head.extend_from_slice(&buf[..read])
+        }
+    }
+    let head = String::from_utf8_lossy(&head);
+    let target = head.split_whitespace().nth(1).unwrap_or("");
+    let username = target.split_once('?').map_or("", |(_, query)| query)
+        .split('&')
+        .filter_map(|kv| match kv.split_once('=') {
+            Some(("username", v)) => Some(v.to_owned()),
+            _ => None
+        }).next();
+    let resp = match username {
+        Some(name) if target.starts_with("/session/minecraft/hasJoined") => {
+            let profile = MockSessionVerifier::profile_for(&name);
+            let body = serde_json::json!({
+                "id": profile.id.to_simple().to_string(),
+                "name": profile.name,
+                "properties": profile.properties
+            }).to_string();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body
+            )
+        },
+        _ => "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_owned()
+    };
+    if let Err(e) = stream.write_all(resp.as_bytes()).await {
+        debug!("Mock session server failed to respond: {:?}", e);
+        return;
+    }
+    // already answered, nothing to do if this fails
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_hash_matches_java() {
+        // digests of the bare names, as listed on wiki.vg
+        assert_eq!(server_hash("Notch", &[], &[]), "4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48");
+        assert_eq!(server_hash("jeb_", &[], &[]), "-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1");
+        assert_eq!(server_hash("simon", &[], &[]), "88e16a1019277b15d58faf0541e11910eb756f6");
+    }
+
+    #[test]
+    fn server_hash_covers_all_parts() {
+        let whole = server_hash("", b"Notch", &[]);
+        assert_eq!(whole, server_hash("Notch", &[], &[]));
+        assert_eq!(whole, server_hash("No", b"t", b"ch"));
+        assert_ne!(whole, server_hash("Notch", b"", b"!"));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use std::{future::Future, sync::Arc};
+use crossbeam::sync::ShardedLock;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Protocol state of a JE connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Handshake,
+    Status,
+    Login,
+    Play
+}
+
+impl ConnectionState {
+    /// Map the `next_state` field of a handshake.
+    pub fn from_next_state(next_state: i32) -> Option<ConnectionState> {
+        match next_state {
+            1 => Some(ConnectionState::Status),
+            2 => Some(ConnectionState::Login),
+            _ => None
+        }
+    }
+}
+
+/// Everything the network thread shares with every connection task.
+pub struct JeNetShared {
+    pub cc: &'static ConfigCollection,
+    pub sp: &'static ServerPrefix,
+    pub rsa_keypair: &'static openssl::rsa::Rsa<openssl::pkey::Private>,
+    pub pubkey_der: &'static Vec<u8>,
+    pub server_json_status: Arc<ShardedLock<ServerJsonStatus>>,
+    pub session_verifier: Arc<dyn SessionVerifier>,
+    pub send_new_conn: UnboundedSender<JeConnection>,
+    /// Closed connections that reached play state, so the network thread can drop them.
+    pub send_end_conn: UnboundedSender<JeConnection>,
+    /// Play packets go straight to the game thread.
+    pub send_game: crossbeam::Sender<NetRecvMsg>,
+    pub moderation: Arc<ShardedLock<JeModeration>>,
+    pub throttle: JeThrottle,
+    pub registries: JeRegistries
+}
+
+/// State of a single JE connection, handed to packet handlers.
+pub struct JeSession {
+    pub addr: SocketAddr,
+    pub framed: JeFramed,
+    pub state: ConnectionState,
+    /// From the handshake, the client's protocol number even if we don't support it.
+    pub protocol_ver: i32,
+    /// Set once the client reaches play state.
+    pub conn: Option<JeConnection>,
+    /// (username, verify token) between Encryption Request and Response
+    pub pending_login: Option<(String, Vec<u8>)>,
+    /// From a BungeeCord handshake, until Login Start.
+    pub forwarded: Option<JeForwardedPlayer>,
+    /// (message id, username) between Velocity's Login Plugin Request and Response
+    pub pending_forward: Option<(i32, String)>,
+    /// Handed to the connection at play state, written out by the connection task.
+    pub outbound: Arc<JeOutboundQueue>,
+    /// Cleared to close the connection.
+    pub run: bool,
+    /// Last time anything was read from the client.
+    pub last_seen: Instant,
+    /// Close the connection if it hasn't reached play state by then.
+    pub login_deadline: Option<Instant>,
+    pub last_keep_alive: Instant,
+    /// (id, sent at) of the keep-alive awaiting a reply
+    pub pending_keep_alive: Option<(i64, Instant)>,
+    /// Held until play state, see `max_pending_connections`.
+    pub pending_slot: Option<JePendingSlot>,
+    pub shared: Arc<JeNetShared>
+}
+
+impl JeSession {
+    pub fn new(addr: SocketAddr, framed: JeFramed, outbound: Arc<JeOutboundQueue>, pending_slot: JePendingSlot, shared: Arc<JeNetShared>) -> JeSession {
+        let now = Instant::now();
+        Self {
+            addr,
+            framed,
+            state: ConnectionState::Handshake,
+            protocol_ver: 0,
+            conn: None,
+            pending_login: None,
+            forwarded: None,
+            pending_forward: None,
+            outbound,
+            run: true,
+            last_seen: now,
+            login_deadline: Some(now + Duration::from_secs(shared.cc.net.login_timeout_secs)),
+            last_keep_alive: now,
+            pending_keep_alive: None,
+            pending_slot: Some(pending_slot),
+            shared
+        }
+    }
+    /// Switch state, in the session and in the codec.
+    pub fn set_state(&mut self, state: ConnectionState) {
+        self.state = state;
+        let protocol = JeProtocol::from_number(self.protocol_ver).unwrap_or_default();
+        self.framed.codec_mut().set_protocol(protocol, state);
+    }
+    /// Write `packet` to the client straight away, rather than through the outbound queue.
+    /// Closes the connection and returns `false` if the write fails.
+    pub async fn write<P: JePacket + Send + Sync>(&mut self, packet: P) -> bool {
+        match packet.write_to_stream(&mut self.framed).await {
+            Ok(()) => true,
+            Err(()) => {
+                debug!("{} write failed, closing", &self.addr);
+                self.run = false;
+                false
+            }
+        }
+    }
+}
+
+/// What vanilla tells a player's previous connection when they log in again.
+pub const JE_LOGGED_IN_ELSEWHERE: &str = "You logged in from another location";
+
+pub enum JeJoin {
+    /// The game thread can have a session for this connection now.
+    Now(JeConnection),
+    /// The player's previous connection has to close first. Holds a connection that was
+    /// already waiting for the same player, which has to be closed too.
+    Wait(Option<JeConnection>)
+}
+
+/// Which connection the game thread has a session for, per player.
+/// A player logging in again waits for the previous connection to close, so the game thread
+/// always gets its EndSession before the next NewSession for the same uuid.
+#[derive(Default)]
+pub struct JeJoins {
+    joined: HashMap<Uuid, Arc<JeOutboundQueue>>,
+    waiting: HashMap<Uuid, JeConnection>
+}
+
+impl JeJoins {
+    /// A connection reached play state.
+    pub fn on_login(&mut self, conn: JeConnection) -> JeJoin {
+        if self.joined.contains_key(&conn.uuid) {
+            JeJoin::Wait(self.waiting.insert(conn.uuid, conn))
+        } else {
+            self.joined.insert(conn.uuid, Arc::clone(&conn.outbound));
+            JeJoin::Now(conn)
+        }
+    }
+    /// A connection that reached play state closed.
+    /// Returns whether the game thread had a session for it, and a connection that can join in its place.
+    pub fn on_close(&mut self, conn: &JeConnection) -> (bool, Option<JeConnection>) {
+        if matches!(self.waiting.get(&conn.uuid), Some(waiting) if Arc::ptr_eq(&waiting.outbound, &conn.outbound)) {
+            self.waiting.remove(&conn.uuid);
+            return (false, None);
+        }
+        match self.joined.get(&conn.uuid) {
+            Some(joined) if Arc::ptr_eq(joined, &conn.outbound) => {
+                self.joined.remove(&conn.uuid);
+                let next = self.waiting.remove(&conn.uuid);
+                if let Some(next) = &next {
+                    self.joined.insert(next.uuid, Arc::clone(&next.outbound));
+                }
+                (true, next)
+            },
+            _ => (false, None)
+        }
+    }
+}
+
+pub type JeHandlerFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+type JeRawHandler = Box<dyn for<'a> Fn(&'a mut JeSession, &[u8]) -> Result<JeHandlerFuture<'a>, JeDecodeError> + Send + Sync>;
+
+#[derive(Debug)]
+pub enum JeDispatchError {
+    /// No handler for this id in this state.
+    UnknownId(ConnectionState, i32),
+    /// The packet failed to decode as the registered type.
+    Malformed(ConnectionState, i32, JeDecodeError)
+}
+
+/// Maps inbound packet ids of one state to their decoder and handler.
+pub struct JePacketRegistry {
+    state: ConnectionState,
+    handlers: HashMap<i32, JeRawHandler>,
+    /// Takes any packet without a registered handler.
+    fallback: Option<for<'a> fn(&'a mut JeSession, i32, &[u8]) -> JeHandlerFuture<'a>>
+}
+
+impl JePacketRegistry {
+    pub fn new(state: ConnectionState) -> JePacketRegistry {
+        Self {
+            state,
+            handlers: HashMap::new(),
+            fallback: None
+        }
+    }
+    /// Handle packets of type `P`, keyed on its packet id.
+    pub fn register<P: JePacket + Default + Send + 'static>(mut self, handler: for<'a> fn(&'a mut JeSession, P) -> JeHandlerFuture<'a>) -> Self {
+        let id = P::default().get_packet_id().0;
+        let replaced = self.handlers.insert(id, Box::new(move |session, data| {
+            P::try_from_raw(data).map(move |packet| handler(session, packet))
+        }));
+        debug_assert!(replaced.is_none(), "packet id {:#04x} registered twice in {:?}", id, self.state);
+        self
+    }
+    pub fn fallback(mut self, handler: for<'a> fn(&'a mut JeSession, i32, &[u8]) -> JeHandlerFuture<'a>) -> Self {
+        self.fallback = Some(handler);
+        self
+    }
+    /// Decode `data` and return the handler's future.
+    pub fn dispatch<'a>(&self, session: &'a mut JeSession, id: i32, data: &[u8]) -> Result<JeHandlerFuture<'a>, JeDispatchError> {
+        match self.handlers.get(&id) {
+            Some(handler) => handler(session, data)
+                .map_err(|e| JeDispatchError::Malformed(self.state, id, e)),
+            None => match self.fallback {
+                Some(fallback) => Ok(fallback(session, id, data)),
+                None => Err(JeDispatchError::UnknownId(self.state, id))
+            }
+        }
+    }
+}
+
+/// One registry per connection state.
+pub struct JeRegistries {
+    pub handshake: JePacketRegistry,
+    pub status: JePacketRegistry,
+    pub login: JePacketRegistry,
+    pub play: JePacketRegistry
+}
+
+impl JeRegistries {
+    pub fn get(&self, state: ConnectionState) -> &JePacketRegistry {
+        match state {
+            ConnectionState::Handshake => &self.handshake,
+            ConnectionState::Status => &self.status,
+            ConnectionState::Login => &self.login,
+            ConnectionState::Play => &self.play
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    fn conn(uuid: Uuid) -> JeConnection {
+        JeConnection {
+            state: ConnectionState::Play,
+            enc: None,
+            uuid,
+            username: "Tester".to_owned(),
+            addr: "127.0.0.1:50000".parse().unwrap(),
+            outbound: JeOutboundQueue::new(&ConfigNet::default()).0,
+            online: false,
+            properties: Vec::new(),
+            latency: Arc::new(AtomicU32::new(0))
+        }
+    }
+
+    #[test]
+    fn login_and_close() {
+        let mut joins = JeJoins::default();
+        let first = conn(Uuid::nil());
+        assert!(matches!(joins.on_login(first.clone()), JeJoin::Now(_)));
+        let (ended, next) = joins.on_close(&first);
+        assert!(ended && next.is_none());
+        // closing twice doesn't end anything
+        assert!(!joins.on_close(&first).0);
+    }
+
+    #[test]
+    fn login_again_waits_for_previous() {
+        let mut joins = JeJoins::default();
+        let first = conn(Uuid::nil());
+        let second = conn(Uuid::nil());
+        let third = conn(Uuid::nil());
+        assert!(matches!(joins.on_login(first.clone()), JeJoin::Now(_)));
+        assert!(matches!(joins.on_login(second.clone()), JeJoin::Wait(None)));
+        // a third login supersedes the waiting one
+        match joins.on_login(third.clone()) {
+            JeJoin::Wait(Some(superseded)) => assert!(Arc::ptr_eq(&superseded.outbound, &second.outbound)),
+            _ => panic!("third login should wait and supersede the second")
+        }
+        let (ended, next) = joins.on_close(&second);
+        assert!(!ended && next.is_none());
+        // the first closing lets the third in, the game thread sees EndSession then NewSession
+        let (ended, next) = joins.on_close(&first);
+        assert!(ended);
+        assert!(Arc::ptr_eq(&next.unwrap().outbound, &third.outbound));
+        assert!(!joins.on_close(&first).0);
+        assert!(joins.on_close(&third).0);
+    }
+
+    #[test]
+    fn players_are_separate() {
+        let mut joins = JeJoins::default();
+        let a = conn(Uuid::nil());
+        let b = conn(Uuid::from_u128(1));
+        assert!(matches!(joins.on_login(a.clone()), JeJoin::Now(_)));
+        assert!(matches!(joins.on_login(b.clone()), JeJoin::Now(_)));
+        assert!(joins.on_close(&a).0);
+        assert!(joins.on_close(&b).0);
+    }
+}
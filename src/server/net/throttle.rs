@@ -0,0 +1,333 @@
+use crate::imports::*;
+use crate::server::symbols::*;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, AtomicUsize, Ordering}};
+
+/// What throttled clients are told, same as vanilla.
+pub const JE_THROTTLED_MESSAGE: &str = "Connection throttled! Please wait before reconnecting.";
+
+/// Running totals for monitoring, shared with the game thread.
+#[derive(Default)]
+pub struct JeThrottleStats {
+    /// Connections let through to the handshake.
+    pub accepted: AtomicU64,
+    /// Closed on accept because the address is blocked.
+    pub refused_blocked: AtomicU64,
+    pub refused_connection_rate: AtomicU64,
+    pub refused_pending_cap: AtomicU64,
+    pub refused_login_rate: AtomicU64,
+    /// Closed for taking too long over a packet.
+    pub slow_senders: AtomicU64,
+    /// Addresses blocked for repeated offenses.
+    pub blocks: AtomicU64,
+    /// Connections not in play state right now.
+    pub pending: AtomicUsize
+}
+
+impl JeThrottleStats {
+    /// One line for the console.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} pending, {} accepted, refused {} blocked / {} connection rate / {} pending cap / {} login rate, {} slow senders, {} blocks",
+            self.pending.load(Ordering::Relaxed),
+            self.accepted.load(Ordering::Relaxed),
+            self.refused_blocked.load(Ordering::Relaxed),
+            self.refused_connection_rate.load(Ordering::Relaxed),
+            self.refused_pending_cap.load(Ordering::Relaxed),
+            self.refused_login_rate.load(Ordering::Relaxed),
+            self.slow_senders.load(Ordering::Relaxed),
+            self.blocks.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JeThrottleRefusal {
+    Blocked,
+    ConnectionRate,
+    PendingCap,
+    LoginRate
+}
+
+/// Counts towards `max_pending_connections` until dropped, i.e. until play state or close.
+pub struct JePendingSlot {
+    stats: Arc<JeThrottleStats>
+}
+
+impl Drop for JePendingSlot {
+    fn drop(&mut self) {
+        self.stats.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct JePeerRecord {
+    window_start: Instant,
+    connections: u32,
+    logins: u32,
+    offenses_since: Instant,
+    offenses: u32,
+    blocked_until: Option<Instant>
+}
+
+impl JePeerRecord {
+    fn new(now: Instant) -> JePeerRecord {
+        Self {
+            window_start: now,
+            connections: 0,
+            logins: 0,
+            offenses_since: now,
+            offenses: 0,
+            blocked_until: None
+        }
+    }
+    fn is_blocked(&self, now: Instant) -> bool {
+        matches!(self.blocked_until, Some(until) if now < until)
+    }
+    /// Start a new window if the current one is over.
+    fn roll(&mut self, now: Instant, window: Duration, block: Duration) {
+        if now.duration_since(self.window_start) >= window {
+            self.window_start = now;
+            self.connections = 0;
+            self.logins = 0;
+        }
+        if now.duration_since(self.offenses_since) >= block {
+            self.offenses_since = now;
+            self.offenses = 0;
+        }
+    }
+}
+
+/// Per-address rate limits, the pending connection cap and auto-blocks for the JE port.
+/// A limit of 0 disables it.
+pub struct JeThrottle {
+    window: Duration,
+    max_connections: u32,
+    max_logins: u32,
+    max_pending: usize,
+    offenses_before_block: u32,
+    block: Duration,
+    peers: Mutex<HashMap<IpAddr, JePeerRecord>>,
+    pub stats: Arc<JeThrottleStats>
+}
+
+impl JeThrottle {
+    pub fn new(net: &ConfigNet, stats: Arc<JeThrottleStats>) -> JeThrottle {
+        Self {
+            window: Duration::from_secs(net.throttle_window_secs),
+            max_connections: net.max_connections_per_ip,
+            max_logins: net.max_logins_per_ip,
+            max_pending: net.max_pending_connections,
+            offenses_before_block: net.throttle_offenses_before_block,
+            block: Duration::from_secs(net.throttle_block_secs),
+            peers: Mutex::new(HashMap::new()),
+            stats
+        }
+    }
+    /// Let a new connection from `ip` through, or not.
+    /// Without `per_ip` only the pending cap applies, for connections from a forwarding proxy.
+    pub fn on_connect(&self, ip: IpAddr, per_ip: bool) -> Result<JePendingSlot, JeThrottleRefusal> {
+        self.on_connect_at(ip, per_ip, Instant::now())
+    }
+    fn on_connect_at(&self, ip: IpAddr, per_ip: bool, now: Instant) -> Result<JePendingSlot, JeThrottleRefusal> {
+        if per_ip {
+            self.count_connection(ip, now)?;
+        }
+        let pending = self.stats.pending.fetch_add(1, Ordering::Relaxed);
+        let slot = JePendingSlot {
+            stats: Arc::clone(&self.stats)
+        };
+        if self.max_pending != 0 && pending >= self.max_pending {
+            self.stats.refused_pending_cap.fetch_add(1, Ordering::Relaxed);
+            return Err(JeThrottleRefusal::PendingCap);
+        }
+        self.stats.accepted.fetch_add(1, Ordering::Relaxed);
+        Ok(slot)
+    }
+    /// Count a client behind a PROXY protocol balancer against the per-address limits.
+    /// The balancer's own connection already holds the pending slot.
+    pub fn on_proxied_connect(&self, ip: IpAddr) -> Result<(), JeThrottleRefusal> {
+        self.count_connection(ip, Instant::now())
+    }
+    fn count_connection(&self, ip: IpAddr, now: Instant) -> Result<(), JeThrottleRefusal> {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(ip).or_insert_with(|| JePeerRecord::new(now));
+        if peer.is_blocked(now) {
+            self.stats.refused_blocked.fetch_add(1, Ordering::Relaxed);
+            return Err(JeThrottleRefusal::Blocked);
+        }
+        peer.roll(now, self.window, self.block);
+        peer.connections += 1;
+        if self.max_connections != 0 && peer.connections > self.max_connections {
+            self.stats.refused_connection_rate.fetch_add(1, Ordering::Relaxed);
+            self.offend(ip, peer, now);
+            return Err(JeThrottleRefusal::ConnectionRate);
+        }
+        Ok(())
+    }
+    /// Count a login attempt from `ip`, the forwarded address if there is one.
+    pub fn on_login(&self, ip: IpAddr) -> Result<(), JeThrottleRefusal> {
+        self.on_login_at(ip, Instant::now())
+    }
+    fn on_login_at(&self, ip: IpAddr, now: Instant) -> Result<(), JeThrottleRefusal> {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(ip).or_insert_with(|| JePeerRecord::new(now));
+        if peer.is_blocked(now) {
+            self.stats.refused_blocked.fetch_add(1, Ordering::Relaxed);
+            return Err(JeThrottleRefusal::Blocked);
+        }
+        peer.roll(now, self.window, self.block);
+        peer.logins += 1;
+        if self.max_logins != 0 && peer.logins > self.max_logins {
+            self.stats.refused_login_rate.fetch_add(1, Ordering::Relaxed);
+            self.offend(ip, peer, now);
+            return Err(JeThrottleRefusal::LoginRate);
+        }
+        Ok(())
+    }
+    /// Count misbehaviour other than going over a rate, e.g. dribbling bytes.
+    pub fn offense(&self, ip: IpAddr) {
+        self.offense_at(ip, Instant::now())
+    }
+    fn offense_at(&self, ip: IpAddr, now: Instant) {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(ip).or_insert_with(|| JePeerRecord::new(now));
+        peer.roll(now, self.window, self.block);
+        self.offend(ip, peer, now);
+    }
+    fn offend(&self, ip: IpAddr, peer: &mut JePeerRecord, now: Instant) {
+        peer.offenses += 1;
+        if self.offenses_before_block != 0 && peer.offenses >= self.offenses_before_block && !peer.is_blocked(now) {
+            warn!("Blocking {} for {:?} after {} offenses", ip, self.block, peer.offenses);
+            peer.blocked_until = Some(now + self.block);
+            peer.offenses = 0;
+            self.stats.blocks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// Forget addresses with nothing left to remember.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.peers.lock().unwrap().retain(|_, peer| {
+            peer.is_blocked(now)
+                || now.duration_since(peer.window_start) < self.window
+                || (peer.offenses != 0 && now.duration_since(peer.offenses_since) < self.block)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+    const BLOCK: Duration = Duration::from_secs(60);
+
+    fn throttle(max_connections: u32, max_logins: u32, max_pending: usize, offenses_before_block: u32) -> JeThrottle {
+        JeThrottle {
+            window: WINDOW,
+            max_connections,
+            max_logins,
+            max_pending,
+            offenses_before_block,
+            block: BLOCK,
+            peers: Mutex::new(HashMap::new()),
+            stats: Arc::new(JeThrottleStats::default())
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    #[test]
+    fn connection_rate() {
+        let throttle = throttle(2, 0, 0, 0);
+        let now = Instant::now();
+        assert!(throttle.on_connect_at(ip(1), true, now).is_ok());
+        assert!(throttle.on_connect_at(ip(1), true, now).is_ok());
+        assert_eq!(throttle.on_connect_at(ip(1), true, now).err(), Some(JeThrottleRefusal::ConnectionRate));
+        // per address
+        assert!(throttle.on_connect_at(ip(2), true, now).is_ok());
+        // not for proxied connections
+        assert!(throttle.on_connect_at(ip(1), false, now).is_ok());
+        // a new window
+        assert!(throttle.on_connect_at(ip(1), true, now + WINDOW).is_ok());
+        assert_eq!(throttle.stats.refused_connection_rate.load(Ordering::Relaxed), 1);
+        assert_eq!(throttle.stats.accepted.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn login_rate() {
+        let throttle = throttle(0, 1, 0, 0);
+        let now = Instant::now();
+        assert!(throttle.on_login_at(ip(1), now).is_ok());
+        assert_eq!(throttle.on_login_at(ip(1), now), Err(JeThrottleRefusal::LoginRate));
+        assert!(throttle.on_login_at(ip(2), now).is_ok());
+        assert!(throttle.on_login_at(ip(1), now + WINDOW).is_ok());
+        assert_eq!(throttle.stats.refused_login_rate.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn blocks_repeat_offenders() {
+        let throttle = throttle(1, 0, 0, 3);
+        let now = Instant::now();
+        assert!(throttle.on_connect_at(ip(1), true, now).is_ok());
+        // two rate offenses and a slow send
+        assert!(throttle.on_connect_at(ip(1), true, now).is_err());
+        assert!(throttle.on_connect_at(ip(1), true, now).is_err());
+        assert_eq!(throttle.stats.blocks.load(Ordering::Relaxed), 0);
+        throttle.offense_at(ip(1), now);
+        assert_eq!(throttle.stats.blocks.load(Ordering::Relaxed), 1);
+        // blocked even in a new window, and for logins
+        let later = now + WINDOW;
+        assert_eq!(throttle.on_connect_at(ip(1), true, later).err(), Some(JeThrottleRefusal::Blocked));
+        assert_eq!(throttle.on_login_at(ip(1), later), Err(JeThrottleRefusal::Blocked));
+        assert!(throttle.on_connect_at(ip(2), true, later).is_ok());
+        assert_eq!(throttle.stats.refused_blocked.load(Ordering::Relaxed), 2);
+        // until the block is over
+        assert!(throttle.on_connect_at(ip(1), true, now + BLOCK).is_ok());
+    }
+
+    #[test]
+    fn offenses_expire() {
+        let throttle = throttle(0, 0, 0, 2);
+        let now = Instant::now();
+        throttle.offense_at(ip(1), now);
+        throttle.offense_at(ip(1), now + BLOCK);
+        assert_eq!(throttle.stats.blocks.load(Ordering::Relaxed), 0);
+        throttle.offense_at(ip(1), now + BLOCK);
+        assert_eq!(throttle.stats.blocks.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn proxied_connections() {
+        let throttle = throttle(1, 0, 1, 0);
+        let now = Instant::now();
+        // the balancer holds the only pending slot, its clients are limited on their own
+        let balancer = throttle.on_connect_at(ip(1), false, now).unwrap();
+        assert!(throttle.count_connection(ip(2), now).is_ok());
+        assert_eq!(throttle.count_connection(ip(2), now), Err(JeThrottleRefusal::ConnectionRate));
+        assert!(throttle.count_connection(ip(3), now).is_ok());
+        assert_eq!(throttle.stats.pending.load(Ordering::Relaxed), 1);
+        drop(balancer);
+    }
+
+    #[test]
+    fn pending_slots() {
+        let throttle = throttle(0, 0, 2, 0);
+        let now = Instant::now();
+        let first = throttle.on_connect_at(ip(1), true, now).unwrap();
+        let second = throttle.on_connect_at(ip(2), false, now).unwrap();
+        assert_eq!(throttle.stats.pending.load(Ordering::Relaxed), 2);
+        assert_eq!(throttle.on_connect_at(ip(3), true, now).err(), Some(JeThrottleRefusal::PendingCap));
+        // the refused connection doesn't hold a slot
+        assert_eq!(throttle.stats.pending.load(Ordering::Relaxed), 2);
+        drop(first);
+        assert_eq!(throttle.stats.pending.load(Ordering::Relaxed), 1);
+        let third = throttle.on_connect_at(ip(3), true, now).unwrap();
+        drop(second);
+        drop(third);
+        assert_eq!(throttle.stats.pending.load(Ordering::Relaxed), 0);
+        assert_eq!(throttle.stats.refused_pending_cap.load(Ordering::Relaxed), 1);
+    }
+}
@@ -3,7 +3,82 @@ use crate::server::symbols::*;
 
 pub trait JeType: Sized {
     fn to_vec_u8(&self) -> Vec<u8>;
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()>;
+    /// Decode a value at the start of `be_bytes`, returning it and the bytes read.
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError>;
+}
+
+/// A value that failed to decode, and where.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JeDecodeError {
+    pub packet: Option<&'static str>,
+    pub field: Option<&'static str>,
+    /// From the start of the packet data, or of the value outside a packet.
+    pub offset: usize,
+    pub cause: JeDecodeCause
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JeDecodeCause {
+    /// The packet ended in the middle of a value.
+    UnexpectedEnd,
+    /// A VarInt or VarLong longer than 5 or 10 bytes.
+    VarNumTooLong,
+    /// A length or count that is negative or runs past the end of the packet.
+    BadLength(i64),
+    /// A Boolean other than 0 or 1.
+    BadBool(u8),
+    BadUtf8,
+    BadIdentifier(String),
+    BadChat(String),
+    BadNbt(String),
+    /// The type can only be encoded.
+    Unsupported
+}
+
+impl JeDecodeError {
+    pub fn new(cause: JeDecodeCause) -> JeDecodeError {
+        Self {
+            packet: None,
+            field: None,
+            offset: 0,
+            cause
+        }
+    }
+    /// For errors in a value `by` bytes into the enclosing one.
+    pub fn after(mut self, by: usize) -> JeDecodeError {
+        self.offset += by;
+        self
+    }
+    pub fn in_packet(mut self, packet: &'static str, field: &'static str) -> JeDecodeError {
+        self.packet = Some(packet);
+        self.field = Some(field);
+        self
+    }
+}
+
+impl std::fmt::Display for JeDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(packet) = self.packet {
+            write!(f, "{}.{} ", packet, self.field.unwrap_or("?"))?;
+        }
+        write!(f, "at byte {}: {}", self.offset, self.cause)
+    }
+}
+
+impl std::fmt::Display for JeDecodeCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JeDecodeCause::UnexpectedEnd => write!(f, "packet ends early"),
+            JeDecodeCause::VarNumTooLong => write!(f, "VarInt too long"),
+            JeDecodeCause::BadLength(len) => write!(f, "bad length {}", len),
+            JeDecodeCause::BadBool(b) => write!(f, "{:#04x} is not a Boolean", b),
+            JeDecodeCause::BadUtf8 => write!(f, "invalid UTF-8"),
+            JeDecodeCause::BadIdentifier(id) => write!(f, "invalid identifier {:?}", id),
+            JeDecodeCause::BadChat(e) => write!(f, "invalid chat component: {}", e),
+            JeDecodeCause::BadNbt(e) => write!(f, "invalid NBT: {}", e),
+            JeDecodeCause::Unsupported => write!(f, "cannot be decoded")
+        }
+    }
 }
 
 // Boolean
@@ -11,12 +86,13 @@ impl JeType for bool {
     fn to_vec_u8(&self) -> Vec<u8> {
         if *self {0b1u8.to_be_bytes().to_vec()} else {0b0u8.to_be_bytes().to_vec()}
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_u8(&mut cursor) {
             Ok(1) => Ok((true, 1)),
             Ok(0) => Ok((false, 1)),
-            Ok(_) | Err(_) => Err(())
+            Ok(b) => Err(JeDecodeError::new(JeDecodeCause::BadBool(b))),
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -26,11 +102,11 @@ impl JeType for i8 {
     fn to_vec_u8(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_i8(&mut cursor) {
             Ok(val) => Ok((val, 1)),
-            Err(_) => Err(())
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -40,11 +116,11 @@ impl JeType for u8 {
     fn to_vec_u8(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_u8(&mut cursor) {
             Ok(val) => Ok((val, 1)),
-            Err(_) => Err(())
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -54,11 +130,11 @@ impl JeType for i16 {
     fn to_vec_u8(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_i16::<byteorder::NetworkEndian>(&mut cursor) {
             Ok(val) => Ok((val, 2)),
-            Err(_) => Err(())
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -68,11 +144,11 @@ impl JeType for u16 {
     fn to_vec_u8(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_u16::<byteorder::NetworkEndian>(&mut cursor) {
             Ok(val) => Ok((val, 2)),
-            Err(_) => Err(())
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -82,11 +158,11 @@ impl JeType for i32 {
     fn to_vec_u8(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_i32::<byteorder::NetworkEndian>(&mut cursor) {
             Ok(val) => Ok((val, 4)),
-            Err(_) => Err(())
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -96,11 +172,11 @@ impl JeType for i64 {
     fn to_vec_u8(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_i64::<byteorder::NetworkEndian>(&mut cursor) {
             Ok(val) => Ok((val, 8)),
-            Err(_) => Err(())
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -110,11 +186,11 @@ impl JeType for f32 {
     fn to_vec_u8(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_f32::<byteorder::NetworkEndian>(&mut cursor) {
             Ok(val) => Ok((val, 4)),
-            Err(_) => Err(())
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -124,11 +200,11 @@ impl JeType for f64 {
     fn to_vec_u8(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let mut cursor = Cursor::new(be_bytes);
         match byteorder::ReadBytesExt::read_f64::<byteorder::NetworkEndian>(&mut cursor) {
             Ok(val) => Ok((val, 8)),
-            Err(_) => Err(())
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
         }
     }
 }
@@ -141,17 +217,20 @@ impl JeType for String {
             self.as_bytes().to_vec()
         ].iter().flatten().map(|e| *e).collect()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
         let (str_len, len_read) = JeVarInt::try_from_raw(be_bytes)?;
         debug!("STRING get VARINT ok len {:?} len_read {}", &str_len, len_read);
-        let (_, be_bytes) = be_bytes.split_at(len_read);
-        debug!("STRING DECODE ARRAY {:?}", &be_bytes);
-        match String::from_utf8(be_bytes[0..str_len.0 as usize].to_owned()) {
+        // the length comes from the client, check it before slicing
+        let str_bytes = match str_len.as_len().ok().and_then(|len| be_bytes[len_read..].get(..len)) {
+            Some(str_bytes) => str_bytes,
+            None => return Err(JeDecodeError::new(JeDecodeCause::BadLength(str_len.0 as i64)))
+        };
+        match String::from_utf8(str_bytes.to_owned()) {
             Ok(str) => {
                 debug!("STRING DECODE OK {}", &str);
-                Ok((str, str_len.0 as usize + len_read))
+                Ok((str, str_bytes.len() + len_read))
             },
-            Err(_) => Err(())
+            Err(e) => Err(JeDecodeError::new(JeDecodeCause::BadUtf8).after(len_read + e.utf8_error().valid_up_to()))
         }
     }
 }
@@ -161,10 +240,11 @@ impl JeType for JeVarInt {
     fn to_vec_u8(&self) -> Vec<u8> {
         crate::server::net::legacy::int_to_var_int(self.0)
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
-        match crate::server::net::legacy::var_int_to_int(be_bytes, 5) {
-            Ok((vi, read)) => return Ok((JeVarInt(vi), read)),
-            Err(_) => return Err(())
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        match peek_var_int(be_bytes) {
+            Ok(Some((vi, read))) => Ok((JeVarInt(vi), read)),
+            Ok(None) => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd)),
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::VarNumTooLong))
         }
     }
 }
@@ -172,57 +252,352 @@ impl JeType for JeVarInt {
 // VarLong
 impl JeType for JeVarLong {
     fn to_vec_u8(&self) -> Vec<u8> {
-        unimplemented!()
+        let mut val = self.0 as u64;
+        let mut result = Vec::with_capacity(10);
+        loop {
+            let temp = (val & 0b0111_1111) as u8;
+            val >>= 7;
+            if val == 0 {
+                result.push(temp);
+                return result;
+            }
+            result.push(temp | 0b1000_0000);
+        }
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
-        unimplemented!()
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        let mut result: u64 = 0;
+        for (iteration, byte) in be_bytes.iter().take(10).enumerate() {
+            result |= ((byte & 0b0111_1111) as u64) << (7 * iteration);
+            if byte & 0b1000_0000 == 0 {
+                return Ok((JeVarLong(result as i64), iteration + 1));
+            }
+        }
+        Err(JeDecodeError::new(if be_bytes.len() >= 10 {
+            JeDecodeCause::VarNumTooLong
+        } else {
+            JeDecodeCause::UnexpectedEnd
+        }))
     }
 }
 
-// Array
-impl JeType for Vec<u8> {
+// Position
+impl JeType for JePosition {
     fn to_vec_u8(&self) -> Vec<u8> {
-        self.to_owned()
+        let packed = ((self.x as i64 & 0x3ff_ffff) << 38)
+            | ((self.z as i64 & 0x3ff_ffff) << 12)
+            | (self.y as i64 & 0xfff);
+        packed.to_vec_u8()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
-        Ok((be_bytes.to_vec(), be_bytes.len()))
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        let (packed, read) = i64::try_from_raw(be_bytes)?;
+        // shifting back down sign-extends each field
+        Ok((JePosition {
+            x: (packed >> 38) as i32,
+            y: (packed << 52 >> 52) as i32,
+            z: (packed << 26 >> 38) as i32
+        }, read))
     }
 }
 
-pub enum JeTypeError {}
+// Angle
+impl JeType for JeAngle {
+    fn to_vec_u8(&self) -> Vec<u8> {
+        self.0.to_vec_u8()
+    }
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        u8::try_from_raw(be_bytes).map(|(steps, read)| (JeAngle(steps), read))
+    }
+}
 
-#[derive(Debug, Default)]
-pub struct JeVarInt(pub i32);
+// UUID
+impl JeType for Uuid {
+    fn to_vec_u8(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        match be_bytes.get(..16) {
+            Some(uuid) => Ok((Uuid::from_slice(uuid).unwrap(), 16)),
+            None => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
+        }
+    }
+}
 
-#[derive(Debug, Default)]
-pub struct JeVarLong(pub i64);
+// Identifier
+impl JeType for JeIdentifier {
+    fn to_vec_u8(&self) -> Vec<u8> {
+        self.0.to_vec_u8()
+    }
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        let (s, read) = String::try_from_raw(be_bytes)?;
+        match JeIdentifier::parse(&s) {
+            Ok(id) => Ok((id, read)),
+            Err(_) => Err(JeDecodeError::new(JeDecodeCause::BadIdentifier(s)))
+        }
+    }
+}
 
-#[derive(Debug, Default)]
-pub struct JeChat(pub String);
+// Optional X, prefixed by a Boolean
+impl<T: JeType> JeType for Option<T> {
+    fn to_vec_u8(&self) -> Vec<u8> {
+        match self {
+            Some(val) => {
+                let mut result = true.to_vec_u8();
+                result.extend(val.to_vec_u8());
+                result
+            },
+            None => false.to_vec_u8()
+        }
+    }
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        match bool::try_from_raw(be_bytes)? {
+            (true, read) => T::try_from_raw(&be_bytes[read..])
+                .map(|(val, val_read)| (Some(val), read + val_read))
+                .map_err(|e| e.after(read)),
+            (false, read) => Ok((None, read))
+        }
+    }
+}
 
-impl JeType for JeChat {
+// Array of X, prefixed by its length as a VarInt
+impl<T: JeType> JeType for Vec<T> {
     fn to_vec_u8(&self) -> Vec<u8> {
-        serde_json::to_string(
-            &serde_json::json!({"text": &self.0})
-        ).unwrap().to_vec_u8()
-    }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
-        match String::try_from_raw(be_bytes) {
-            Ok((s, l)) => {
-                if let Ok(chat) = serde_json::from_str::<serde_json::Value>(&s) {
-                    if let Some(content) = chat.get("text") {
-                        if let Some(actual_content) = content.as_str() {
-                            return Ok((JeChat(actual_content.to_owned()), l));
-                        }
-                    }
+        let mut result = JeVarInt(self.len() as i32).to_vec_u8();
+        result.extend(self.write_uncounted());
+        result
+    }
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        let (len, len_read) = JeVarInt::try_from_raw(be_bytes)?;
+        Self::read_counted(&be_bytes[len_read..], len.as_len()?)
+            .map(|(result, read)| (result, len_read + read))
+            .map_err(|e| e.after(len_read))
+    }
+}
+
+/// Fields that can hold the element count of another field.
+pub trait JeLength {
+    fn as_len(&self) -> Result<usize, JeDecodeError>;
+}
+
+macro_rules! impl_je_length {
+    ($($t:ty),*) => {
+        $(impl JeLength for $t {
+            fn as_len(&self) -> Result<usize, JeDecodeError> {
+                if *self < 0 {
+                    Err(JeDecodeError::new(JeDecodeCause::BadLength(*self as i64)))
+                } else {
+                    Ok(*self as usize)
                 }
-                Err(())
+            }
+        })*
+    };
+}
+
+impl_je_length!(i8, i16, i32, i64);
+
+impl JeLength for u8 {
+    fn as_len(&self) -> Result<usize, JeDecodeError> {
+        Ok(*self as usize)
+    }
+}
+
+impl JeLength for u16 {
+    fn as_len(&self) -> Result<usize, JeDecodeError> {
+        Ok(*self as usize)
+    }
+}
+
+impl JeLength for JeVarInt {
+    fn as_len(&self) -> Result<usize, JeDecodeError> {
+        self.0.as_len()
+    }
+}
+
+/// Arrays whose length is in another field.
+pub trait JeCountedArray: Sized {
+    fn read_counted(be_bytes: &[u8], count: usize) -> Result<(Self, usize), JeDecodeError>;
+    fn write_uncounted(&self) -> Vec<u8>;
+}
+
+impl<T: JeType> JeCountedArray for Vec<T> {
+    fn read_counted(be_bytes: &[u8], count: usize) -> Result<(Self, usize), JeDecodeError> {
+        // every element is at least a byte, don't trust the count for the allocation
+        let mut result = Vec::with_capacity(count.min(be_bytes.len()));
+        let mut counter = 0;
+        for _ in 0..count {
+            let (val, read) = T::try_from_raw(&be_bytes[counter..]).map_err(|e| e.after(counter))?;
+            result.push(val);
+            counter += read;
+        }
+        Ok((result, counter))
+    }
+    fn write_uncounted(&self) -> Vec<u8> {
+        self.iter().flat_map(|val| val.to_vec_u8()).collect()
+    }
+}
+
+/// Optional fields whose presence depends on another field.
+pub trait JeConditional: Sized {
+    fn read_if(be_bytes: &[u8], present: bool) -> Result<(Self, usize), JeDecodeError>;
+    fn write_if(&self) -> Vec<u8>;
+}
+
+impl<T: JeType> JeConditional for Option<T> {
+    fn read_if(be_bytes: &[u8], present: bool) -> Result<(Self, usize), JeDecodeError> {
+        if present {
+            T::try_from_raw(be_bytes).map(|(val, read)| (Some(val), read))
+        } else {
+            Ok((None, 0))
+        }
+    }
+    fn write_if(&self) -> Vec<u8> {
+        match self {
+            Some(val) => val.to_vec_u8(),
+            None => vec![]
+        }
+    }
+}
+
+// Byte array without a length, running to the end of the packet
+impl JeType for JeRemainingBytes {
+    fn to_vec_u8(&self) -> Vec<u8> {
+        self.0.to_owned()
+    }
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        Ok((JeRemainingBytes(be_bytes.to_vec()), be_bytes.len()))
+    }
+}
+
+// NBT
+impl JeType for JeNbt {
+    fn to_vec_u8(&self) -> Vec<u8> {
+        match &self.0 {
+            Some(blob) => {
+                let mut result = Vec::with_capacity(blob.len_bytes());
+                blob.to_writer(&mut result).unwrap();
+                result
             },
-            Err(_) => Err(())
+            // TAG_End on its own
+            None => vec![0]
         }
     }
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        match be_bytes.first() {
+            Some(0) => Ok((JeNbt(None), 1)),
+            Some(_) => {
+                let mut cursor = Cursor::new(be_bytes);
+                nbt::Blob::from_reader(&mut cursor)
+                    .map(|blob| (JeNbt(Some(blob)), cursor.position() as usize))
+                    .map_err(|e| JeDecodeError::new(JeDecodeCause::BadNbt(e.to_string())))
+            },
+            None => Err(JeDecodeError::new(JeDecodeCause::UnexpectedEnd))
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JeVarInt(pub i32);
+
+#[derive(Debug, Default, PartialEq)]
+pub struct JeVarLong(pub i64);
+
+/// Block position, packed into a Long on the wire.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JePosition {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32
+}
+
+/// Rotation in steps of 1/256 of a full turn.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JeAngle(pub u8);
+
+impl JeAngle {
+    pub fn from_degrees(degrees: f32) -> JeAngle {
+        JeAngle((degrees.rem_euclid(360.0) / 360.0 * 256.0) as i32 as u8)
+    }
+    pub fn to_degrees(&self) -> f32 {
+        self.0 as f32 * 360.0 / 256.0
+    }
+}
+
+/// Namespaced id such as `minecraft:stone`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct JeIdentifier(String);
+
+impl JeIdentifier {
+    /// Accepts `path` alone as `minecraft:path`.
+    pub fn parse(id: &str) -> Result<JeIdentifier, ()> {
+        let (namespace, path) = match id.find(':') {
+            Some(split) => (&id[..split], &id[split + 1..]),
+            None => ("minecraft", id)
+        };
+        let valid_namespace = namespace.chars().all(|c| matches!(c, 'a'..='z' | '0'..='9' | '.' | '-' | '_'));
+        let valid_path = path.chars().all(|c| matches!(c, 'a'..='z' | '0'..='9' | '.' | '-' | '_' | '/'));
+        if namespace.is_empty() || path.is_empty() || !valid_namespace || !valid_path {
+            return Err(());
+        }
+        Ok(JeIdentifier(format!("{}:{}", namespace, path)))
+    }
+    pub fn namespace(&self) -> &str {
+        &self.0[..self.0.find(':').unwrap()]
+    }
+    pub fn path(&self) -> &str {
+        &self.0[self.0.find(':').unwrap() + 1..]
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for JeIdentifier {
+    fn default() -> Self {
+        JeIdentifier("minecraft:air".to_owned())
+    }
+}
+
+/// The rest of the packet as is, e.g. plugin message data.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct JeRemainingBytes(pub Vec<u8>);
+
+/// An NBT compound, or `None` for a lone TAG_End (e.g. an item without tags).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct JeNbt(pub Option<nbt::Blob>);
+
+/// A chat component on the wire, as a JSON string.
+#[derive(Debug, Default, Clone)]
+pub struct JeChat(pub JeChatComponent);
+
+impl JeChat {
+    /// Unformatted text.
+    pub fn text(text: &str) -> JeChat {
+        JeChat(JeChatComponent::text(text))
+    }
+    /// Text with `§` formatting codes.
+    pub fn legacy(text: &str) -> JeChat {
+        JeChat(JeChatComponent::from_legacy(text, &[LEGACY_SECTION]))
+    }
+}
+
+impl From<JeChatComponent> for JeChat {
+    fn from(component: JeChatComponent) -> Self {
+        JeChat(component)
+    }
 }
 
+impl JeType for JeChat {
+    fn to_vec_u8(&self) -> Vec<u8> {
+        serde_json::to_string(&self.0).unwrap().to_vec_u8()
+    }
+    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        let (s, l) = String::try_from_raw(be_bytes)?;
+        serde_json::from_str::<JeChatComponent>(&s)
+            .map(|component| (JeChat(component), l))
+            .map_err(|e| JeDecodeError::new(JeDecodeCause::BadChat(e.to_string())))
+    }
+}
+
+#[derive(Debug)]
 pub enum JeLevelType {
     Default,
     Flat,
@@ -251,7 +626,128 @@ impl JeType for JeLevelType {
             JeLevelType::Default_1_1 => "default_1_1"
         }).to_vec_u8()
     }
-    fn try_from_raw(be_bytes: &[u8]) -> Result<(Self, usize), ()> {
-        Err(())
+    fn try_from_raw(_: &[u8]) -> Result<(Self, usize), JeDecodeError> {
+        Err(JeDecodeError::new(JeDecodeCause::Unsupported))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: JeType + PartialEq + std::fmt::Debug>(val: T, bytes: &[u8]) {
+        assert_eq!(val.to_vec_u8(), bytes, "encoding {:?}", &val);
+        assert_eq!(T::try_from_raw(bytes), Ok((val, bytes.len())));
+    }
+
+    #[test]
+    fn string() {
+        round_trip("".to_owned(), &[0x00]);
+        round_trip("héllo".to_owned(), &[0x06, b'h', 0xc3, 0xa9, b'l', b'l', b'o']);
+        // lengths past the end or negative, and bad UTF-8 at its byte
+        assert_eq!(String::try_from_raw(&[0xff, 0xff, 0x03, b'a']).unwrap_err().cause, JeDecodeCause::BadLength(65535));
+        assert_eq!(String::try_from_raw(&[0xff, 0xff, 0xff, 0xff, 0x0f]).unwrap_err().cause, JeDecodeCause::BadLength(-1));
+        assert_eq!(String::try_from_raw(&[0x02, b'a', 0xff]).unwrap_err().offset, 2);
+    }
+
+    #[test]
+    fn var_long() {
+        round_trip(JeVarLong(0), &[0x00]);
+        round_trip(JeVarLong(1), &[0x01]);
+        round_trip(JeVarLong(127), &[0x7f]);
+        round_trip(JeVarLong(128), &[0x80, 0x01]);
+        round_trip(JeVarLong(255), &[0xff, 0x01]);
+        round_trip(JeVarLong(2147483647), &[0xff, 0xff, 0xff, 0xff, 0x07]);
+        round_trip(JeVarLong(9223372036854775807), &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+        round_trip(JeVarLong(-1), &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        round_trip(JeVarLong(-2147483648), &[0x80, 0x80, 0x80, 0x80, 0xf8, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        round_trip(JeVarLong(-9223372036854775808), &[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]);
+        // too long, or cut short
+        assert!(JeVarLong::try_from_raw(&[0xff; 11]).is_err());
+        assert!(JeVarLong::try_from_raw(&[0x80, 0x80]).is_err());
+    }
+
+    #[test]
+    fn position() {
+        // example from wiki.vg
+        round_trip(JePosition { x: 18357644, y: 831, z: -20882616 }, &[0x46, 0x07, 0x63, 0x2c, 0x15, 0xb4, 0x83, 0x3f]);
+        round_trip(JePosition { x: 0, y: 0, z: 0 }, &[0; 8]);
+        round_trip(JePosition { x: -1, y: -1, z: -1 }, &[0xff; 8]);
+        round_trip(JePosition { x: 1, y: 2, z: 3 }, &[0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x30, 0x02]);
+    }
+
+    #[test]
+    fn angle() {
+        round_trip(JeAngle(0), &[0x00]);
+        round_trip(JeAngle(64), &[0x40]);
+        assert_eq!(JeAngle::from_degrees(90.0), JeAngle(64));
+        assert_eq!(JeAngle::from_degrees(-90.0), JeAngle(192));
+        assert_eq!(JeAngle(128).to_degrees(), 180.0);
+    }
+
+    #[test]
+    fn uuid() {
+        round_trip(
+            Uuid::parse_str("069a79f4-44e9-4726-a5be-fca90e38aaf5").unwrap(),
+            &[0x06, 0x9a, 0x79, 0xf4, 0x44, 0xe9, 0x47, 0x26, 0xa5, 0xbe, 0xfc, 0xa9, 0x0e, 0x38, 0xaa, 0xf5]
+        );
+        assert!(Uuid::try_from_raw(&[0; 15]).is_err());
+    }
+
+    #[test]
+    fn identifier() {
+        let mut bytes = vec![15];
+        bytes.extend_from_slice(b"minecraft:stone");
+        round_trip(JeIdentifier::parse("minecraft:stone").unwrap(), &bytes);
+        assert_eq!(JeIdentifier::parse("stone"), JeIdentifier::parse("minecraft:stone"));
+        let id = JeIdentifier::parse("craftmine:worlds/overworld").unwrap();
+        assert_eq!((id.namespace(), id.path()), ("craftmine", "worlds/overworld"));
+        assert!(JeIdentifier::parse("Minecraft:stone").is_err());
+        assert!(JeIdentifier::parse("minecraft:").is_err());
+        assert!(JeIdentifier::try_from_raw(&[3, b'a', b':', b'B']).is_err());
+    }
+
+    #[test]
+    fn option() {
+        round_trip(None::<i32>, &[0x00]);
+        round_trip(Some(1i32), &[0x01, 0x00, 0x00, 0x00, 0x01]);
+        round_trip(Some(JeVarLong(128)), &[0x01, 0x80, 0x01]);
+        assert!(Option::<i32>::try_from_raw(&[0x02, 0, 0, 0, 1]).is_err());
+        assert!(Option::<i32>::try_from_raw(&[0x01, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn prefixed_array() {
+        round_trip(Vec::<i16>::new(), &[0x00]);
+        round_trip(vec![1i16, -2], &[0x02, 0x00, 0x01, 0xff, 0xfe]);
+        round_trip(vec![0xcau8, 0xfe], &[0x02, 0xca, 0xfe]);
+        round_trip(vec![Some(true), None], &[0x02, 0x01, 0x01, 0x00]);
+        // stops at the prefixed length
+        assert_eq!(Vec::<u8>::try_from_raw(&[0x01, 0xaa, 0xbb]), Ok((vec![0xaa], 2)));
+        assert!(Vec::<u8>::try_from_raw(&[0x03, 0xaa]).is_err());
+        assert!(Vec::<u8>::try_from_raw(&[0xff, 0xff, 0xff, 0xff, 0x0f]).is_err());
+        // offset of the failing element
+        assert_eq!(Vec::<bool>::try_from_raw(&[0x03, 0x00, 0x01, 0x02]).unwrap_err(), JeDecodeError {
+            packet: None,
+            field: None,
+            offset: 3,
+            cause: JeDecodeCause::BadBool(2)
+        });
+    }
+
+    #[test]
+    fn remaining_bytes() {
+        round_trip(JeRemainingBytes(vec![1, 2, 3]), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn nbt() {
+        round_trip(JeNbt(None), &[0x00]);
+        round_trip(JeNbt(Some(nbt::Blob::new())), &[0x0a, 0x00, 0x00, 0x00]);
+        let mut blob = nbt::Blob::new();
+        blob.insert("a", 1i8).unwrap();
+        round_trip(JeNbt(Some(blob)), &[0x0a, 0x00, 0x00, 0x01, 0x00, 0x01, b'a', 0x01, 0x00]);
+        // followed by other fields
+        assert_eq!(JeNbt::try_from_raw(&[0x0a, 0x00, 0x00, 0x00, 0xff]).map(|(_, read)| read), Ok(4));
+        assert!(JeNbt::try_from_raw(&[0x0a, 0x00, 0x00]).is_err());
+    }
+}
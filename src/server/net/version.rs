@@ -0,0 +1,156 @@
+use crate::server::symbols::*;
+use bytes::Bytes;
+
+/// Client versions we can talk to, oldest first.
+///
+/// Packets are declared and built in the 1.15.2 layout. The codec swaps ids and rewrites
+/// fields on the way in and out for other versions, so nothing above it needs to care.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JeProtocol {
+    V1_14_4,
+    V1_15_2
+}
+
+pub const JE_PROTOCOLS: [JeProtocol; 2] = [JeProtocol::V1_14_4, JeProtocol::V1_15_2];
+
+/// Play state ids as (1.15.2 id, this version's id).
+/// Every clientbound packet we declare has to be listed, even if its id didn't change,
+/// others are dropped on the way out. Add new ones here along with the packet.
+struct JeIdTable {
+    clientbound: &'static [(i32, i32)],
+    serverbound: &'static [(i32, i32)]
+}
+
+const JE_IDS_1_14_4: JeIdTable = JeIdTable {
+    // 1.15 moved Acknowledge Player Digging to 0x08, pushing the ids after it up by one
+    clientbound: &[
+        (0x0f, 0x0e), // Chat Message
+        (0x1b, 0x1a), // Disconnect
+        (0x21, 0x20), // Keep Alive
+        (0x22, 0x21), // Chunk Data
+        (0x26, 0x25)  // Join Game
+    ],
+    serverbound: &[]
+};
+
+const JE_IDS_1_15_2: JeIdTable = JeIdTable {
+    clientbound: &[],
+    serverbound: &[]
+};
+
+impl JeProtocol {
+    /// Every 1.15.x release shares one protocol apart from the number.
+    pub fn from_number(protocol_ver: i32) -> Option<JeProtocol> {
+        match protocol_ver {
+            498 => Some(JeProtocol::V1_14_4),
+            573 | 575 | 578 => Some(JeProtocol::V1_15_2),
+            _ => None
+        }
+    }
+    pub fn number(&self) -> i32 {
+        match self {
+            JeProtocol::V1_14_4 => 498,
+            JeProtocol::V1_15_2 => 578
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            JeProtocol::V1_14_4 => "1.14.4",
+            JeProtocol::V1_15_2 => "1.15.2"
+        }
+    }
+    pub fn oldest() -> JeProtocol {
+        JE_PROTOCOLS[0]
+    }
+    pub fn newest() -> JeProtocol {
+        JE_PROTOCOLS[JE_PROTOCOLS.len() - 1]
+    }
+    /// e.g. `1.14.4-1.15.2`, for the server list and disconnect messages.
+    pub fn supported_range() -> String {
+        format!("{}-{}", Self::oldest().name(), Self::newest().name())
+    }
+    fn ids(&self) -> &'static JeIdTable {
+        match self {
+            JeProtocol::V1_14_4 => &JE_IDS_1_14_4,
+            JeProtocol::V1_15_2 => &JE_IDS_1_15_2
+        }
+    }
+    /// Turn an outbound 1.15.2 packet into this version's.
+    /// `None` for Play packets missing from this version's table, whatever the id means there.
+    pub fn to_wire(&self, state: ConnectionState, id: i32, data: Bytes) -> Option<(i32, Bytes)> {
+        if state != ConnectionState::Play {
+            // same from 1.13 to 1.15
+            return Some((id, data));
+        }
+        let data = match (self, id) {
+            // Join Game without hashed seed and enable respawn screen, both added in 1.15
+            (JeProtocol::V1_14_4, 0x26) if data.len() >= 18 => {
+                let mut old = Vec::with_capacity(data.len() - 9);
+                old.extend_from_slice(&data[..9]);
+                old.extend_from_slice(&data[17..data.len() - 1]);
+                Bytes::from(old)
+            },
+            _ => data
+        };
+        let id = match self.ids().clientbound.iter().find(|(canonical, _)| *canonical == id) {
+            Some((_, wire)) => *wire,
+            // packets are declared in this layout
+            None if *self == JeProtocol::V1_15_2 => id,
+            None => return None
+        };
+        Some((id, data))
+    }
+    /// Map an inbound packet id to its 1.15.2 one.
+    pub fn from_wire(&self, state: ConnectionState, id: i32) -> i32 {
+        if state != ConnectionState::Play {
+            return id;
+        }
+        match self.ids().serverbound.iter().find(|(_, wire)| *wire == id) {
+            Some((canonical, _)) => *canonical,
+            None => id
+        }
+    }
+}
+
+impl Default for JeProtocol {
+    fn default() -> Self {
+        JeProtocol::newest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every clientbound Play packet we declare.
+    fn clientbound_play() -> Vec<i32> {
+        vec![
+            JePlayDisconnect::default().get_packet_id().0,
+            JeJoinGame::default().get_packet_id().0,
+            JeChunk::default().get_packet_id().0,
+            JeKeepAlive::default().get_packet_id().0,
+            JePlayChatOut::default().get_packet_id().0
+        ]
+    }
+
+    #[test]
+    fn every_clientbound_id_is_mapped() {
+        for protocol in &JE_PROTOCOLS {
+            for id in clientbound_play() {
+                assert!(
+                    protocol.to_wire(ConnectionState::Play, id, Bytes::new()).is_some(),
+                    "{:#04x} has no {} id", id, protocol.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unmapped_ids_are_dropped() {
+        // Entity Position, not declared yet
+        assert!(JeProtocol::V1_14_4.to_wire(ConnectionState::Play, 0x29, Bytes::new()).is_none());
+        assert_eq!(JeProtocol::V1_15_2.to_wire(ConnectionState::Play, 0x29, Bytes::new()).unwrap().0, 0x29);
+        assert_eq!(JeProtocol::V1_14_4.to_wire(ConnectionState::Login, 0x29, Bytes::new()).unwrap().0, 0x29);
+        assert_eq!(JeProtocol::V1_14_4.to_wire(ConnectionState::Play, 0x0f, Bytes::new()).unwrap().0, 0x0e);
+    }
+}
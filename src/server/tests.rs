@@ -0,0 +1,210 @@
+//! End to end tests, with `JeClient` against a whole server on loopback.
+
+use crate::imports::*;
+use crate::server::symbols::*;
+use crate::init_flags::InitFlags;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A server started through `ServerInitializer` in its own temp prefix, on free loopback ports.
+struct TestServer {
+    addr: SocketAddr,
+    prefix: PathBuf,
+    cli_send: crossbeam::Sender<CliCommand>,
+    send_status: crossbeam::Sender<ServerStatus>
+}
+
+impl TestServer {
+    /// Offline mode unless `configure` says otherwise.
+    fn start(configure: impl FnOnce(&mut ConfigAuth, &mut ConfigNet)) -> TestServer {
+        static NEXT_PREFIX: AtomicUsize = AtomicUsize::new(0);
+        let prefix = std::env::temp_dir().join(format!(
+            "craftmine-test-{}-{}", std::process::id(), NEXT_PREFIX.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&prefix);
+        let config = prefix.join("config");
+        std::fs::create_dir_all(&config).unwrap();
+        let mut auth = ConfigAuth::default();
+        auth.online_mode = false;
+        let mut net = ConfigNet::default();
+        configure(&mut auth, &mut net);
+        write_config(&config, &auth);
+        write_config(&config, &net);
+        let je_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let be_port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let init = ServerInitializer(InitFlags {
+            prefix: Some(prefix.clone()),
+            config_path: None,
+            je_port: Some(je_port),
+            be_port: Some(be_port),
+            daemon: false,
+            bind_addr: Some("127.0.0.1".to_owned())
+        });
+        let (gs, chans) = match init.start().instance {
+            Ok(instance) => instance,
+            Err(errors) => panic!("Server failed to start: {:?}", errors)
+        };
+        std::thread::spawn(move || {
+            let mut gs = gs;
+            gs.run();
+        });
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], je_port)),
+            prefix,
+            cli_send: chans.cli_send,
+            send_status: chans.send_status
+        }
+    }
+    /// Connect as a 1.15.2 client, retrying while the network thread is still binding.
+    async fn connect(&self) -> JeClient {
+        self.connect_as(JeProtocol::newest().number()).await
+    }
+    async fn connect_as(&self, protocol_ver: i32) -> JeClient {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match JeClient::connect(self.addr, protocol_ver).await {
+                Ok(client) => return client,
+                Err(e) if Instant::now() < deadline => {
+                    debug!("Test server not up yet: {:?}", e);
+                    tokio::time::delay_for(Duration::from_millis(50)).await;
+                },
+                Err(e) => panic!("Failed to connect to test server: {:?}", e)
+            }
+        }
+    }
+    /// Console command output.
+    fn command(&self, line: &str) -> String {
+        let (reply, output) = crossbeam::bounded(1);
+        self.cli_send.send(CliCommand {
+            line: line.to_owned(),
+            reply
+        }).unwrap();
+        output.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+    /// Wait for `list` to show `username` as online or not.
+    async fn wait_for_player(&self, username: &str, online: bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if self.command("list").contains(username) == online {
+                return true;
+            }
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }
+        false
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.send_status.send(ServerStatus::Stop);
+        let _ = std::fs::remove_dir_all(&self.prefix);
+    }
+}
+
+fn write_config<T: ConfigFile>(dir: &Path, config: &T) {
+    let file = std::fs::File::create(T::get_path(dir)).unwrap();
+    serde_json::to_writer(file, config).unwrap();
+}
+
+#[tokio::test]
+async fn status_json() {
+    let server = TestServer::start(|_, net| {
+        net.server_description = "Integration test".to_owned();
+    });
+    let mut client = server.connect().await;
+    let status = client.status().await.unwrap();
+    assert_eq!(status["version"]["protocol"], JeProtocol::newest().number());
+    assert!(status["version"]["name"].as_str().unwrap().contains(&JeProtocol::supported_range()));
+    assert_eq!(status["players"]["max"], ConfigAuth::default().max_players);
+    assert_eq!(status["players"]["online"], 0);
+    assert!(status["description"].to_string().contains("Integration test"));
+}
+
+#[tokio::test]
+async fn status_echoes_supported_protocol() {
+    let server = TestServer::start(|_, _| {});
+    let oldest = JeProtocol::oldest().number();
+    let status = server.connect_as(oldest).await.status().await.unwrap();
+    assert_eq!(status["version"]["protocol"], oldest);
+    let status = server.connect_as(340).await.status().await.unwrap();
+    assert_eq!(status["version"]["protocol"], JeProtocol::newest().number());
+}
+
+#[tokio::test]
+async fn ping_pong() {
+    let server = TestServer::start(|_, _| {});
+    let mut client = server.connect().await;
+    client.status().await.unwrap();
+    assert_eq!(client.ping(0x1234_5678_9abc).await.unwrap(), 0x1234_5678_9abc);
+}
+
+#[tokio::test]
+async fn offline_login() {
+    let server = TestServer::start(|_, _| {});
+    let mut client = server.connect().await;
+    let success = client.login("Tester").await.unwrap();
+    assert_eq!(success.username, "Tester");
+    assert!(Uuid::parse_str(&success.uuid).is_ok());
+    assert!(server.wait_for_player("Tester", true).await);
+}
+
+#[tokio::test]
+async fn online_login_with_mock_session_server() {
+    let server = TestServer::start(|auth, _| {
+        auth.online_mode = true;
+        auth.session_server_mock = true;
+    });
+    let mut client = server.connect().await;
+    let success = client.login("Tester").await.unwrap();
+    assert_eq!(success.uuid, MockSessionVerifier::profile_for("Tester").id.to_hyphenated().to_string());
+}
+
+#[tokio::test]
+async fn outdated_client_is_refused() {
+    let server = TestServer::start(|_, _| {});
+    let mut client = server.connect_as(340).await;
+    client.handshake(ConnectionState::Login).await.unwrap();
+    match client.expect::<JeLoginSuccess>().await {
+        Err(JeClientError::Disconnected(reason)) => assert!(reason.starts_with("Outdated client!"), "{}", reason),
+        other => panic!("Expected a disconnect, got {:?}", other.map(|success| success.username))
+    }
+}
+
+#[tokio::test]
+async fn kick_disconnects_player() {
+    let server = TestServer::start(|_, _| {});
+    let mut client = server.connect().await;
+    client.login("Tester").await.unwrap();
+    assert!(server.wait_for_player("Tester", true).await);
+    assert_eq!(server.command("kick Tester Testing kicks"), "Kicked Tester: Testing kicks");
+    let reason = tokio::time::timeout(Duration::from_secs(5), client.until_disconnect()).await.unwrap().unwrap();
+    assert_eq!(reason, "Testing kicks");
+    assert!(server.wait_for_player("Tester", false).await);
+}
+
+#[tokio::test]
+async fn closing_connection_ends_session() {
+    let server = TestServer::start(|_, _| {});
+    let mut client = server.connect().await;
+    client.login("Tester").await.unwrap();
+    assert!(server.wait_for_player("Tester", true).await);
+    drop(client);
+    assert!(server.wait_for_player("Tester", false).await);
+}
+
+#[tokio::test]
+async fn capture_replays_cleanly() {
+    let server = TestServer::start(|_, net| {
+        net.capture_packets = true;
+    });
+    let mut client = server.connect().await;
+    client.status().await.unwrap();
+    client.ping(42).await.unwrap();
+    drop(client);
+    let captures = server.prefix.join("captures");
+    let capture = std::fs::read_dir(&captures).unwrap().next().unwrap().unwrap().path();
+    let records = read_capture(&capture).unwrap();
+    // handshake, status request and response, ping and pong
+    assert_eq!(records.len(), 5);
+    let diffs = replay_capture(server.addr, &records, Duration::from_secs(5)).await.unwrap();
+    assert!(diffs.is_empty(), "{:?}", diffs);
+}